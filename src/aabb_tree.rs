@@ -0,0 +1,825 @@
+use crate::kd_tree_traits::*;
+#[cfg(test)]
+use crate::ray_box::*;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::ControlFlow;
+
+const DEFAULT_MARGIN: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AabbTreeError {
+    InvalidMargin, // Negative or not finite
+    NonFiniteBounds,
+    DegenerateBounds, // Lower bound above the upper one
+}
+
+impl std::fmt::Display for AabbTreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AabbTreeError::InvalidMargin => write!(f, "margin is negative or not finite"),
+            AabbTreeError::NonFiniteBounds => write!(f, "bounding box is not finite"),
+            AabbTreeError::DegenerateBounds => write!(f, "bounding box is degenerate"),
+        }
+    }
+}
+
+impl std::error::Error for AabbTreeError {}
+
+// Leaves keep their index for as long as they are in the tree, so it doubles as handle.
+// Released nodes change generation, so handles don't resolve to later leaves or internal nodes.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AabbTreeHandle {
+    pub(crate) index: u32,
+    pub(crate) generation: u32,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct AabbNode<BoundingBox: BoundingBoxTrait, Content> {
+    enclosure: BoundingBox, // Enlarged by the margin for leaves
+    parent: Option<u32>,
+    children: Option<[u32; 2]>,
+    height: u32,
+    content: Option<Content>,
+}
+
+// Bounding volume hierarchy over contents, each content is stored in exactly one leaf.
+// Leaves are enlarged by a margin so small movements don't change the tree.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(try_from = "AabbTreeData<BoundingBox, Content>")
+)]
+pub struct AabbTree<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> {
+    nodes: Vec<AabbNode<BoundingBox, Content>>,
+    generations: Vec<u32>,
+    free: Vec<u32>,
+    root: Option<u32>,
+    margin: f64,
+    size: usize,
+}
+
+// Deserialized trees are only accepted once their links are consistent
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct AabbTreeData<BoundingBox: BoundingBoxTrait, Content> {
+    nodes: Vec<AabbNode<BoundingBox, Content>>,
+    generations: Vec<u32>,
+    free: Vec<u32>,
+    root: Option<u32>,
+    margin: f64,
+    size: usize,
+}
+
+#[cfg(feature = "serde")]
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    TryFrom<AabbTreeData<BoundingBox, Content>> for AabbTree<BoundingBox, Content>
+{
+    type Error = &'static str;
+
+    fn try_from(data: AabbTreeData<BoundingBox, Content>) -> Result<Self, Self::Error> {
+        let nodes = &data.nodes;
+        if Self::validate_margin(data.margin).is_err() {
+            return Err("margin is negative or not finite");
+        }
+        if u32::try_from(nodes.len()).is_err() {
+            return Err("too many nodes");
+        }
+        if data.generations.len() != nodes.len() {
+            return Err("nodes don't match their generations");
+        }
+
+        // Every node is either free or reached exactly once from the root
+        let mut is_seen = vec![false; nodes.len()];
+        for &index in &data.free {
+            match nodes.get(index as usize) {
+                Some(node)
+                    if !is_seen[index as usize]
+                        && node.children.is_none()
+                        && node.content.is_none() =>
+                {
+                    is_seen[index as usize] = true
+                }
+                _ => return Err("free list refers to a used or missing node"),
+            }
+        }
+
+        let mut leaf_count = 0;
+        let mut stack: Vec<(Option<u32>, u32)> =
+            data.root.map(|root| (None, root)).into_iter().collect();
+        while let Some((parent, index)) = stack.pop() {
+            let node = match nodes.get(index as usize) {
+                Some(node) if !is_seen[index as usize] && node.parent == parent => node,
+                _ => return Err("node is linked inconsistently"),
+            };
+            is_seen[index as usize] = true;
+            if Self::validate(&node.enclosure).is_err() {
+                return Err("node bounds are not finite or degenerate");
+            }
+
+            match (node.children, &node.content) {
+                (None, Some(_)) if node.height == 0 => leaf_count += 1,
+                (Some(children), None) => {
+                    let child_height = |child: u32| nodes.get(child as usize).map(|c| c.height);
+                    if child_height(children[0])
+                        .max(child_height(children[1]))
+                        .map(|h| h + 1)
+                        != Some(node.height)
+                    {
+                        return Err("node height doesn't match its children");
+                    }
+                    stack.extend(children.iter().map(|&child| (Some(index), child)));
+                }
+                _ => return Err("node is neither a leaf nor an internal node"),
+            }
+        }
+
+        if is_seen.iter().any(|is_seen| !is_seen) {
+            return Err("node is neither free nor in the tree");
+        }
+        if leaf_count != data.size {
+            return Err("size doesn't match the leaves");
+        }
+
+        Ok(AabbTree {
+            nodes: data.nodes,
+            generations: data.generations,
+            free: data.free,
+            root: data.root,
+            margin: data.margin,
+            size: data.size,
+        })
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> Default
+    for AabbTree<BoundingBox, Content>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    AabbTree<BoundingBox, Content>
+{
+    pub fn new() -> Self {
+        Self::with_margin(DEFAULT_MARGIN)
+    }
+
+    pub fn with_margin(margin: f64) -> Self {
+        match Self::try_with_margin(margin) {
+            Ok(aabb_tree) => aabb_tree,
+            Err(error) => panic!("Can't create AabbTree: {}", error),
+        }
+    }
+
+    pub fn try_with_margin(margin: f64) -> Result<Self, AabbTreeError> {
+        Self::validate_margin(margin)?;
+        Ok(AabbTree {
+            nodes: vec![],
+            generations: vec![],
+            free: vec![],
+            root: None,
+            margin,
+            size: 0,
+        })
+    }
+
+    fn validate_margin(margin: f64) -> Result<(), AabbTreeError> {
+        if margin.is_finite() && margin >= 0.0 {
+            Ok(())
+        } else {
+            Err(AabbTreeError::InvalidMargin)
+        }
+    }
+
+    fn validate(enclosure: &BoundingBox) -> Result<(), AabbTreeError> {
+        for i in 0..BoundingBox::DIMENSIONS {
+            let (lower, upper) = enclosure.get_bounds(i);
+            if !lower.is_finite() || !upper.is_finite() {
+                return Err(AabbTreeError::NonFiniteBounds);
+            }
+            if lower > upper {
+                return Err(AabbTreeError::DegenerateBounds);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // A single leaf has height 0
+    pub fn height(&self) -> u32 {
+        self.root.map_or(0, |root| self.nodes[root as usize].height)
+    }
+
+    fn handle(&self, index: u32) -> AabbTreeHandle {
+        AabbTreeHandle {
+            index,
+            generation: self.generations[index as usize],
+        }
+    }
+
+    // The leaf a handle refers to, if it is still in the tree
+    fn leaf(&self, handle: AabbTreeHandle) -> Option<u32> {
+        if self.generations.get(handle.index as usize) != Some(&handle.generation) {
+            return None;
+        }
+        match self.node(handle.index) {
+            AabbNode {
+                children: None,
+                content: Some(_),
+                ..
+            } => Some(handle.index),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, handle: AabbTreeHandle) -> Option<&Content> {
+        let leaf = self.leaf(handle)?;
+        self.node(leaf).content.as_ref()
+    }
+
+    fn fatten(&self, enclosure: &BoundingBox) -> BoundingBox {
+        let mut result = enclosure.clone();
+        for i in 0..BoundingBox::DIMENSIONS {
+            let (lower, upper) = enclosure.get_bounds(i);
+            result.set_bounds(i, (lower - self.margin, upper + self.margin));
+        }
+        result
+    }
+
+    fn allocate(&mut self, node: AabbNode<BoundingBox, Content>) -> u32 {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index as usize] = node;
+                index
+            }
+            None => {
+                let index =
+                    u32::try_from(self.nodes.len()).expect("AabbTree node indices are exhausted");
+                self.nodes.push(node);
+                self.generations.push(0);
+                index
+            }
+        }
+    }
+
+    fn release(&mut self, index: u32) {
+        let node = &mut self.nodes[index as usize];
+        node.parent = None;
+        node.children = None;
+        node.content = None;
+        let generation = &mut self.generations[index as usize];
+        *generation = generation.wrapping_add(1);
+        self.free.push(index);
+    }
+
+    fn node(&self, index: u32) -> &AabbNode<BoundingBox, Content> {
+        &self.nodes[index as usize]
+    }
+
+    fn node_mut(&mut self, index: u32) -> &mut AabbNode<BoundingBox, Content> {
+        &mut self.nodes[index as usize]
+    }
+
+    fn replace_child(&mut self, parent: Option<u32>, old: u32, new: u32) {
+        match parent {
+            Some(parent) => {
+                let children = self.node_mut(parent).children.as_mut().unwrap();
+                let position = if children[0] == old { 0 } else { 1 };
+                children[position] = new;
+            }
+            None => self.root = Some(new),
+        }
+    }
+
+    fn refit(&mut self, index: u32) {
+        let [left, right] = self.node(index).children.unwrap();
+        let enclosure = self.node(left).enclosure.union(&self.node(right).enclosure);
+        let height = 1 + self.node(left).height.max(self.node(right).height);
+        let node = self.node_mut(index);
+        node.enclosure = enclosure;
+        node.height = height;
+    }
+
+    // Lifts the higher grandchild of `index` if the subtrees differ in height by more than one.
+    // Returns the index now at the position of `index`.
+    fn balance(&mut self, index: u32) -> u32 {
+        let [left, right] = match self.node(index).children {
+            Some(children) if self.node(index).height >= 2 => children,
+            _ => return index,
+        };
+
+        let difference = self.node(right).height as i64 - self.node(left).height as i64;
+        let (lifted, kept, side) = if difference > 1 {
+            (right, left, 1)
+        } else if difference < -1 {
+            (left, right, 0)
+        } else {
+            return index;
+        };
+
+        let [first, second] = self.node(lifted).children.unwrap();
+        let (higher, lower) = if self.node(first).height > self.node(second).height {
+            (first, second)
+        } else {
+            (second, first)
+        };
+
+        // `lifted` takes the place of `index`, which keeps `kept` and the lower grandchild
+        let parent = self.node(index).parent;
+        self.replace_child(parent, index, lifted);
+        self.node_mut(lifted).parent = parent;
+        self.node_mut(lifted).children = Some([index, higher]);
+        self.node_mut(index).parent = Some(lifted);
+
+        let mut children = [kept, kept];
+        children[side] = lower;
+        self.node_mut(index).children = Some(children);
+        self.node_mut(lower).parent = Some(index);
+
+        self.refit(index);
+        self.refit(lifted);
+        lifted
+    }
+
+    fn fix_upwards(&mut self, mut index: Option<u32>) {
+        while let Some(current) = index {
+            let current = self.balance(current);
+            self.refit(current);
+            index = self.node(current).parent;
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: u32) {
+        let root = match self.root {
+            Some(root) => root,
+            None => {
+                self.root = Some(leaf);
+                self.node_mut(leaf).parent = None;
+                return;
+            }
+        };
+
+        // Descend towards the sibling that grows the summed surface area the least
+        let enclosure = self.node(leaf).enclosure.clone();
+        let mut sibling = root;
+        while let Some([left, right]) = self.node(sibling).children {
+            let area = self.node(sibling).enclosure.get_surface_area();
+            let combined = self
+                .node(sibling)
+                .enclosure
+                .union(&enclosure)
+                .get_surface_area();
+            let cost = 2.0 * combined;
+            let inheritance = 2.0 * (combined - area);
+
+            let child_cost = |child: u32| {
+                let child = self.node(child);
+                let grown = child.enclosure.union(&enclosure).get_surface_area();
+                if child.children.is_none() {
+                    grown + inheritance
+                } else {
+                    grown - child.enclosure.get_surface_area() + inheritance
+                }
+            };
+            let left_cost = child_cost(left);
+            let right_cost = child_cost(right);
+
+            if cost < left_cost && cost < right_cost {
+                break;
+            }
+            sibling = if left_cost < right_cost { left } else { right };
+        }
+
+        let old_parent = self.node(sibling).parent;
+        let parent = self.allocate(AabbNode {
+            enclosure: enclosure.union(&self.node(sibling).enclosure),
+            parent: old_parent,
+            children: Some([sibling, leaf]),
+            height: self.node(sibling).height + 1,
+            content: None,
+        });
+        self.replace_child(old_parent, sibling, parent);
+        self.node_mut(sibling).parent = Some(parent);
+        self.node_mut(leaf).parent = Some(parent);
+
+        self.fix_upwards(old_parent);
+    }
+
+    fn remove_leaf(&mut self, leaf: u32) {
+        let parent = match self.node(leaf).parent {
+            Some(parent) => parent,
+            None => {
+                self.root = None;
+                return;
+            }
+        };
+
+        let [left, right] = self.node(parent).children.unwrap();
+        let sibling = if left == leaf { right } else { left };
+        let grandparent = self.node(parent).parent;
+
+        self.replace_child(grandparent, parent, sibling);
+        self.node_mut(sibling).parent = grandparent;
+        self.node_mut(leaf).parent = None;
+        self.release(parent);
+
+        self.fix_upwards(grandparent);
+    }
+
+    pub fn add(&mut self, content: Content) -> AabbTreeHandle {
+        match self.try_add(content) {
+            Ok(handle) => handle,
+            Err(error) => panic!("Can't add content to AabbTree: {}", error),
+        }
+    }
+
+    pub fn try_add(&mut self, content: Content) -> Result<AabbTreeHandle, AabbTreeError> {
+        let enclosure = content.get_bounding_box();
+        Self::validate(&enclosure)?;
+        let enclosure = self.fatten(&enclosure);
+        let leaf = self.allocate(AabbNode {
+            enclosure,
+            parent: None,
+            children: None,
+            height: 0,
+            content: Some(content),
+        });
+        self.insert_leaf(leaf);
+        self.size += 1;
+        Ok(self.handle(leaf))
+    }
+
+    pub fn remove_handle(&mut self, handle: AabbTreeHandle) -> Option<Content> {
+        let leaf = self.leaf(handle)?;
+        self.remove_leaf(leaf);
+        let content = self.node_mut(leaf).content.take();
+        self.release(leaf);
+        self.size -= 1;
+        content
+    }
+
+    // Replaces the content of a leaf, which is only reinserted if it left its enlarged box.
+    // Returns whether the tree had to be changed.
+    pub fn move_handle(&mut self, handle: AabbTreeHandle, content: Content) -> bool {
+        let leaf = match self.leaf(handle) {
+            Some(leaf) => leaf,
+            None => return false,
+        };
+
+        let enclosure = content.get_bounding_box();
+        if let Err(error) = Self::validate(&enclosure) {
+            panic!("Can't move content in AabbTree: {}", error);
+        }
+        self.node_mut(leaf).content = Some(content);
+        if enclosure.is_contained(&self.node(leaf).enclosure) {
+            return false;
+        }
+
+        self.remove_leaf(leaf);
+        self.node_mut(leaf).enclosure = self.fatten(&enclosure);
+        self.insert_leaf(leaf);
+        true
+    }
+
+    fn query<B, F>(&self, filter: &BoundingBox, mut fun: F) -> ControlFlow<B>
+    where
+        F: FnMut(u32, &Content) -> ControlFlow<B>,
+    {
+        let mut stack: Vec<u32> = self.root.into_iter().collect();
+        while let Some(index) = stack.pop() {
+            let node = self.node(index);
+            if !node.enclosure.intersects(filter) {
+                continue;
+            }
+
+            match (&node.children, &node.content) {
+                (Some(children), _) => stack.extend_from_slice(children),
+                (None, Some(content)) => {
+                    if content.get_bounding_box().intersects(filter) {
+                        fun(index, content)?;
+                    }
+                }
+                (None, None) => (),
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    pub fn query_with<B, F>(&self, filter: &BoundingBox, mut fun: F) -> ControlFlow<B>
+    where
+        F: FnMut(&Content) -> ControlFlow<B>,
+    {
+        self.query(filter, |_, content| fun(content))
+    }
+
+    pub fn get_intersection_handles(&self, filter: &BoundingBox) -> Vec<AabbTreeHandle> {
+        let mut result = vec![];
+        let _: ControlFlow<()> = self.query(filter, |index, _| {
+            result.push(self.handle(index));
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    pub fn get_intersection(&self, filter: &BoundingBox) -> HashSet<Content>
+    where
+        Content: Clone + Eq + Hash,
+    {
+        let mut result = HashSet::new();
+        let _: ControlFlow<()> = self.query(filter, |_, content| {
+            result.insert(content.clone());
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    fn collect_pairs_between(
+        &self,
+        a: u32,
+        b: u32,
+        result: &mut Vec<(AabbTreeHandle, AabbTreeHandle)>,
+    ) {
+        let (first, second) = (self.node(a), self.node(b));
+        if !first.enclosure.intersects(&second.enclosure) {
+            return;
+        }
+
+        match (first.children, second.children) {
+            (None, None) => {
+                let first_enclosure = first.content.as_ref().unwrap().get_bounding_box();
+                let second_enclosure = second.content.as_ref().unwrap().get_bounding_box();
+                if first_enclosure.intersects(&second_enclosure) {
+                    result.push((self.handle(a.min(b)), self.handle(a.max(b))));
+                }
+            }
+            // Descend into the higher node to keep both sides of similar size
+            (Some([left, right]), _)
+                if second.children.is_none() || first.height >= second.height =>
+            {
+                self.collect_pairs_between(left, b, result);
+                self.collect_pairs_between(right, b, result);
+            }
+            (_, Some([left, right])) => {
+                self.collect_pairs_between(a, left, result);
+                self.collect_pairs_between(a, right, result);
+            }
+            (Some(_), None) => unreachable!(),
+        }
+    }
+
+    fn collect_pairs(&self, index: u32, result: &mut Vec<(AabbTreeHandle, AabbTreeHandle)>) {
+        if let Some([left, right]) = self.node(index).children {
+            self.collect_pairs(left, result);
+            self.collect_pairs(right, result);
+            self.collect_pairs_between(left, right, result);
+        }
+    }
+
+    // Every pair of contents with intersecting bounding boxes, the smaller handle first
+    pub fn query_pairs(&self) -> Vec<(AabbTreeHandle, AabbTreeHandle)> {
+        let mut result = vec![];
+        if let Some(root) = self.root {
+            self.collect_pairs(root, &mut result);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+fn check_aabb_tree<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>(
+    tree: &AabbTree<BoundingBox, Content>,
+) {
+    let mut leaves = 0;
+    let mut stack: Vec<u32> = tree.root.into_iter().collect();
+    while let Some(index) = stack.pop() {
+        let node = tree.node(index);
+        match node.children {
+            Some([left, right]) => {
+                assert_eq!(tree.node(left).parent, Some(index));
+                assert_eq!(tree.node(right).parent, Some(index));
+                let (l, r) = (tree.node(left).height, tree.node(right).height);
+                assert_eq!(node.height, 1 + l.max(r));
+                assert!(l.abs_diff(r) <= 1);
+                assert!(tree.node(left).enclosure.is_contained(&node.enclosure));
+                assert!(tree.node(right).enclosure.is_contained(&node.enclosure));
+                stack.extend_from_slice(&[left, right]);
+            }
+            None => {
+                let content = node.content.as_ref().unwrap();
+                assert!(content.get_bounding_box().is_contained(&node.enclosure));
+                leaves += 1;
+            }
+        }
+    }
+    assert_eq!(leaves, tree.len());
+}
+
+#[test]
+fn test_aabb_tree_pairs() {
+    let boxes: Vec<BoundingBox2d> = (0..400)
+        .map(|i| {
+            let i = i as f64;
+            let u = Point2d::new_raw((0.37 * i).sin() * 20.0, (0.91 * i).cos() * 20.0);
+            let size = Point2d::new_raw(0.5 + (0.13 * i).sin().abs(), 0.5 + (1.7 * i).cos().abs());
+            BoundingBox2d::new(u, u + size)
+        })
+        .collect();
+
+    let brute_force = |tree: &AabbTree<BoundingBox2d, BoundingBox2d>| {
+        let contents: Vec<(AabbTreeHandle, &BoundingBox2d)> = (0..tree.nodes.len() as u32)
+            .map(|index| tree.handle(index))
+            .filter_map(|handle| Some((handle, tree.get(handle)?)))
+            .collect();
+        let mut result = vec![];
+        for (i, (a, first)) in contents.iter().enumerate() {
+            for (b, second) in &contents[i + 1..] {
+                if first.intersects(second) {
+                    result.push((*a.min(b), *a.max(b)));
+                }
+            }
+        }
+        result.sort();
+        result
+    };
+    let sorted_pairs = |tree: &AabbTree<BoundingBox2d, BoundingBox2d>| {
+        let mut pairs = tree.query_pairs();
+        pairs.sort();
+        pairs
+    };
+
+    let mut tree = AabbTree::new();
+    let handles: Vec<AabbTreeHandle> = boxes.iter().map(|b| tree.add(b.clone())).collect();
+    check_aabb_tree(&tree);
+    let expected = brute_force(&tree);
+    assert!(expected.len() > 50);
+    assert_eq!(sorted_pairs(&tree), expected);
+
+    for handle in handles.iter().step_by(3) {
+        assert!(tree.remove_handle(*handle).is_some());
+    }
+    assert!(tree.remove_handle(handles[0]).is_none());
+    // Released slots are reused by new leaves and internal nodes, old handles stay invalid
+    let added = tree.add(boxes[0].clone());
+    let stale = *handles
+        .iter()
+        .find(|handle| handle.index == added.index)
+        .unwrap();
+    assert_ne!(stale, added);
+    assert!(tree.get(stale).is_none());
+    assert!(!tree.move_handle(stale, boxes[0].clone()));
+    assert!(tree.remove_handle(stale).is_none());
+    assert!(tree.remove_handle(added).is_some());
+    let internal = tree.handle(tree.root.unwrap());
+    assert!(tree.get(internal).is_none());
+    assert!(tree.remove_handle(internal).is_none());
+    assert!(!tree.move_handle(internal, boxes[0].clone()));
+    assert_eq!(tree.len(), 400 - 134);
+    check_aabb_tree(&tree);
+    assert_eq!(sorted_pairs(&tree), brute_force(&tree));
+
+    // Small steps stay within the margin, large ones move the leaf
+    let offset = Point2d::new_raw(0.05, -0.05);
+    let moved = BoundingBox2d::new(boxes[1].u + offset, boxes[1].v + offset);
+    assert!(!tree.move_handle(handles[1], moved.clone()));
+    assert_eq!(tree.get(handles[1]).unwrap().u, moved.u);
+    for (i, handle) in handles.iter().enumerate().filter(|(i, _)| i % 3 != 0) {
+        let offset = Point2d::new_raw((i as f64).cos() * 5.0, 3.0);
+        let moved = BoundingBox2d::new(boxes[i].u + offset, boxes[i].v + offset);
+        assert!(tree.move_handle(*handle, moved));
+    }
+    check_aabb_tree(&tree);
+    assert_eq!(sorted_pairs(&tree), brute_force(&tree));
+}
+
+#[test]
+fn test_aabb_tree_balance() {
+    // Inserting along a line degenerates into a list without rotations
+    let mut tree = AabbTree::<BoundingBox3d, Point3d>::with_margin(0.0);
+    let points: Vec<Point3d> = (0..1024)
+        .map(|i| Point3d::new_raw(i as f64, 0.5 * i as f64, 0.0))
+        .collect();
+    let handles: Vec<AabbTreeHandle> = points.iter().map(|point| tree.add(*point)).collect();
+    check_aabb_tree(&tree);
+    assert!(tree.height() <= 20);
+
+    let filter = BoundingBox3d {
+        u: Point3d::new_raw(99.5, 0.0, -1.0),
+        v: Point3d::new_raw(200.0, 99.0, 1.0),
+    };
+    let mut found = tree.get_intersection_handles(&filter);
+    found.sort();
+    assert_eq!(found, handles[100..=198].to_vec());
+    assert_eq!(tree.get_intersection(&filter).len(), 99);
+
+    for handle in &handles[..1000] {
+        tree.remove_handle(*handle);
+    }
+    check_aabb_tree(&tree);
+    assert!(tree.height() <= 6);
+    assert_eq!(tree.get_intersection_handles(&filter), vec![]);
+}
+
+#[test]
+fn test_aabb_tree_try_add() {
+    for margin in [-0.1, f64::NAN, f64::INFINITY] {
+        assert_eq!(
+            AabbTree::<BoundingBox2d, Point2d>::try_with_margin(margin).err(),
+            Some(AabbTreeError::InvalidMargin)
+        );
+    }
+
+    let mut tree = AabbTree::<BoundingBox2d, BoundingBox2d>::try_with_margin(0.0).unwrap();
+    let valid = BoundingBox2d::new(Point2d::new_raw(0.0, 0.0), Point2d::new_raw(1.0, 1.0));
+    let handle = tree.try_add(valid.clone()).unwrap();
+
+    let nan = Point2d::new_raw(f64::NAN, 0.0);
+    let infinite = Point2d::new_raw(0.0, f64::NEG_INFINITY);
+    let cases = [
+        (
+            BoundingBox2d { u: nan, v: nan },
+            AabbTreeError::NonFiniteBounds,
+        ),
+        (
+            BoundingBox2d {
+                u: infinite,
+                v: valid.v,
+            },
+            AabbTreeError::NonFiniteBounds,
+        ),
+        (
+            BoundingBox2d {
+                u: valid.v,
+                v: valid.u,
+            },
+            AabbTreeError::DegenerateBounds,
+        ),
+    ];
+    for (content, error) in cases {
+        assert_eq!(tree.try_add(content).err(), Some(error));
+    }
+    assert_eq!(tree.len(), 1);
+    assert_eq!(tree.height(), 0);
+    assert_eq!(tree.get_intersection_handles(&valid), vec![handle]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_aabb_tree_serde() {
+    let mut tree = AabbTree::<BoundingBox3d, Point3d>::new();
+    let points: Vec<Point3d> = (0..100)
+        .map(|i| Point3d::new_raw((0.37 * i as f64).sin() * 5.0, 0.1 * i as f64, 0.0))
+        .collect();
+    let handles: Vec<AabbTreeHandle> = points.iter().map(|point| tree.add(*point)).collect();
+    for handle in handles.iter().step_by(7) {
+        tree.remove_handle(*handle);
+    }
+
+    let value = serde_json::to_value(&tree).unwrap();
+    let copy: AabbTree<BoundingBox3d, Point3d> = serde_json::from_value(value.clone()).unwrap();
+    check_aabb_tree(&copy);
+    assert_eq!(copy.len(), tree.len());
+    assert_eq!(copy.get(handles[1]), Some(&points[1]));
+    let mut pairs = copy.query_pairs();
+    let mut expected = tree.query_pairs();
+    pairs.sort();
+    expected.sort();
+    assert_eq!(pairs, expected);
+
+    // Payloads breaking the links between the nodes are rejected
+    let root = value["root"].as_u64().unwrap() as usize;
+    let leaf = handles[1].index as usize;
+    let (u, v) = (
+        value["nodes"][leaf]["enclosure"]["u"].clone(),
+        value["nodes"][leaf]["enclosure"]["v"].clone(),
+    );
+    let changes: [&dyn Fn(&mut serde_json::Value); 9] = [
+        &|value| value["margin"] = serde_json::json!(-1.0),
+        &|value| {
+            value["nodes"][leaf]["enclosure"]["u"] = v.clone();
+            value["nodes"][leaf]["enclosure"]["v"] = u.clone();
+        },
+        &|value| value["root"] = serde_json::json!(100000),
+        &|value| value["size"] = serde_json::json!(3),
+        &|value| value["free"] = serde_json::json!([leaf]),
+        &|value| value["generations"] = serde_json::json!([]),
+        &|value| value["nodes"][leaf]["parent"] = serde_json::json!(leaf),
+        &|value| value["nodes"][root]["height"] = serde_json::json!(1),
+        &|value| value["nodes"][root]["children"] = serde_json::json!([root, leaf]),
+    ];
+    for change in changes {
+        let mut value = value.clone();
+        change(&mut value);
+        assert!(serde_json::from_value::<AabbTree<BoundingBox3d, Point3d>>(value).is_err());
+    }
+}
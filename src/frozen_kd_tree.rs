@@ -0,0 +1,359 @@
+use crate::kd_tree::*;
+use crate::kd_tree_traits::*;
+use crate::kd_tree_traversal::*;
+#[cfg(test)]
+use crate::ray_box::*;
+#[cfg(test)]
+use crate::test_shapes::*;
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use std::ops::ControlFlow;
+
+// Children of a node are stored next to each other, so a range is enough to address them
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct FrozenKdNode<BoundingBox: BoundingBoxTrait> {
+    enclosure: BoundingBox,
+    first_child: u32,
+    child_count: u32,
+    first_content: u32,
+    content_count: u32,
+}
+
+// Read-only KdTree with all nodes in one array and all leaf contents in one index array.
+// Handles of the KdTree it was created from stay valid.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(try_from = "FrozenKdTreeData<BoundingBox, Content>")
+)]
+pub struct FrozenKdTree<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> {
+    nodes: Vec<FrozenKdNode<BoundingBox>>,
+    content_indices: Vec<u32>,
+    contents: Vec<Option<Content>>,
+    generations: Vec<u32>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct FrozenKdTreeData<BoundingBox: BoundingBoxTrait, Content> {
+    nodes: Vec<FrozenKdNode<BoundingBox>>,
+    content_indices: Vec<u32>,
+    contents: Vec<Option<Content>>,
+    generations: Vec<u32>,
+}
+
+#[cfg(feature = "serde")]
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    TryFrom<FrozenKdTreeData<BoundingBox, Content>> for FrozenKdTree<BoundingBox, Content>
+{
+    type Error = &'static str;
+
+    fn try_from(data: FrozenKdTreeData<BoundingBox, Content>) -> Result<Self, Self::Error> {
+        if data.generations.len() < data.contents.len() {
+            return Err("content slots don't match their generations");
+        }
+        if data
+            .content_indices
+            .iter()
+            .any(|&index| !matches!(data.contents.get(index as usize), Some(Some(_))))
+        {
+            return Err("node refers to an empty or missing slot");
+        }
+
+        // Children always come after their parent, so the nodes can't form a cycle
+        for (i, node) in data.nodes.iter().enumerate() {
+            let content_end = node.first_content as usize + node.content_count as usize;
+            if content_end > data.content_indices.len() {
+                return Err("node content lies outside the content indices");
+            }
+            let child_end = node.first_child as usize + node.child_count as usize;
+            if node.child_count > 0
+                && (node.first_child as usize <= i || child_end > data.nodes.len())
+            {
+                return Err("node children lie outside the nodes");
+            }
+        }
+
+        Ok(FrozenKdTree {
+            nodes: data.nodes,
+            content_indices: data.content_indices,
+            contents: data.contents,
+            generations: data.generations,
+        })
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    KdTree<BoundingBox, Content>
+{
+    pub fn freeze(self) -> FrozenKdTree<BoundingBox, Content> {
+        let mut nodes = vec![];
+        let mut content_indices = vec![];
+
+        if let Some(root) = &self.root {
+            let mut queue: VecDeque<&KdNode<BoundingBox>> = VecDeque::new();
+            queue.push_back(root);
+            let mut next_free = 1;
+
+            while let Some(node) = queue.pop_front() {
+                nodes.push(FrozenKdNode {
+                    enclosure: node.enclosure.clone(),
+                    first_child: next_free,
+                    child_count: node.children.len() as u32,
+                    first_content: content_indices.len() as u32,
+                    content_count: node.content.len() as u32,
+                });
+                next_free += node.children.len() as u32;
+                content_indices.extend_from_slice(&node.content);
+                queue.extend(node.children.iter());
+            }
+        }
+
+        FrozenKdTree {
+            nodes,
+            content_indices,
+            contents: self.contents,
+            generations: self.generations,
+        }
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    KdTreeNodes<BoundingBox, Content> for FrozenKdTree<BoundingBox, Content>
+{
+    type Node<'a>
+        = &'a FrozenKdNode<BoundingBox>
+    where
+        Self: 'a;
+
+    fn get_root(&self) -> Option<Self::Node<'_>> {
+        self.nodes.first()
+    }
+
+    fn get_enclosure<'a>(&'a self, node: Self::Node<'a>) -> &'a BoundingBox {
+        &node.enclosure
+    }
+
+    fn get_node_content<'a>(&'a self, node: Self::Node<'a>) -> &'a [u32] {
+        let first = node.first_content as usize;
+        &self.content_indices[first..first + node.content_count as usize]
+    }
+
+    fn get_child_count<'a>(&'a self, node: Self::Node<'a>) -> usize {
+        node.child_count as usize
+    }
+
+    fn get_child<'a>(&'a self, node: Self::Node<'a>, i: usize) -> Self::Node<'a> {
+        &self.nodes[node.first_child as usize + i]
+    }
+
+    fn get_content(&self, index: u32) -> &Content {
+        self.contents[index as usize].as_ref().unwrap()
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    FrozenKdTree<BoundingBox, Content>
+{
+    pub fn get(&self, handle: KdTreeHandle) -> Option<&Content> {
+        handle.resolve(&self.contents, &self.generations)
+    }
+
+    pub fn query_with<B, F>(&self, filter: &BoundingBox, mut fun: F) -> ControlFlow<B>
+    where
+        F: FnMut(&Content) -> ControlFlow<B>,
+    {
+        self.query(filter, |_, content| fun(content))
+    }
+
+    pub fn get_intersection_handles(&self, filter: &BoundingBox) -> Vec<KdTreeHandle> {
+        let mut result = vec![];
+        let _: ControlFlow<()> = self.query(filter, |index, _| {
+            result.push(KdTreeHandle::new(index, &self.generations));
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    pub fn get_intersection(&self, filter: &BoundingBox) -> HashSet<Content>
+    where
+        Content: Clone + Eq + Hash,
+    {
+        let mut result = HashSet::new();
+        let _: ControlFlow<()> = self.query(filter, |_, content| {
+            result.insert(content.clone());
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    pub fn get_in_region_handles<R>(&self, region: &R) -> Vec<KdTreeHandle>
+    where
+        R: KdTreeRegion<BoundingBox, Content>,
+    {
+        self.get_in_region_indices(region)
+            .into_iter()
+            .map(|index| KdTreeHandle::new(index, &self.generations))
+            .collect()
+    }
+
+    pub fn get_in_region<R>(&self, region: &R) -> HashSet<Content>
+    where
+        R: KdTreeRegion<BoundingBox, Content>,
+        Content: Clone + Eq + Hash,
+    {
+        self.get_in_region_indices(region)
+            .into_iter()
+            .map(|index| self.get_content(index).clone())
+            .collect()
+    }
+}
+
+impl<BoundingBox: HittableBoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    FrozenKdTree<BoundingBox, Content>
+{
+    pub fn get_closest_hit_handle<F>(
+        &self,
+        fun: &F,
+        cray: &BoundingBox::Ray,
+    ) -> Option<KdTreeHandle>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+    {
+        self.get_closest_hit_index(fun, cray)
+            .map(|index| KdTreeHandle::new(index, &self.generations))
+    }
+
+    pub fn get_closest_hit<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Option<Content>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+        Content: Clone,
+    {
+        self.get_closest_hit_handle(fun, cray)
+            .map(|handle| self.get_content(handle.index).clone())
+    }
+
+    pub fn get_any_hit_handle<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Option<KdTreeHandle>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+    {
+        self.get_any_hit_index(fun, cray)
+            .map(|index| KdTreeHandle::new(index, &self.generations))
+    }
+
+    pub fn get_any_hit<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Option<Content>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+        Content: Clone,
+    {
+        self.get_any_hit_handle(fun, cray)
+            .map(|handle| self.get_content(handle.index).clone())
+    }
+}
+
+#[test]
+fn test_frozen_kd_tree() {
+    let spheres = get_test_spheres();
+
+    let mut added = KdTree::<BoundingBox3d, TestSphere>::new();
+    for sphere in &spheres {
+        added.add(sphere.clone());
+    }
+    let built = KdTree::build(spheres.clone());
+
+    let filters: Vec<BoundingBox3d> = (0..10)
+        .map(|i| BoundingBox3d {
+            u: Point3d::new_raw(0.37 * i as f64, 0.3 * i as f64, -0.2),
+            v: Point3d::new_raw(0.37 * i as f64 + 2.0, 0.3 * i as f64 + 0.5, 40.7),
+        })
+        .collect();
+    let crays: Vec<ConstrainedRay3d> = (0..20)
+        .map(|i| ConstrainedRay3d {
+            ray: Ray3d::new(
+                Point3d::new_raw(-5.0, 0.17 * i as f64, 0.3),
+                Point3d::new_raw(1.0, 0.01 * i as f64, 0.02),
+            ),
+            range: (0.0, 100.0),
+        })
+        .collect();
+
+    let expected_intersections: Vec<_> =
+        filters.iter().map(|f| added.get_intersection(f)).collect();
+    let expected_hits: Vec<_> = crays
+        .iter()
+        .map(|cray| added.get_closest_hit(&hit_test_sphere, cray))
+        .collect();
+
+    for frozen in [added.freeze(), built.freeze()] {
+        for (filter, expected) in filters.iter().zip(&expected_intersections) {
+            assert_eq!(&frozen.get_intersection(filter), expected);
+            assert_eq!(
+                frozen.get_intersection_handles(filter).len(),
+                expected.len()
+            );
+        }
+        for (cray, expected) in crays.iter().zip(&expected_hits) {
+            assert_eq!(&frozen.get_closest_hit(&hit_test_sphere, cray), expected);
+            assert_eq!(
+                frozen.get_any_hit(&hit_test_sphere, cray).is_some(),
+                expected.is_some()
+            );
+        }
+    }
+
+    let mut added = KdTree::<BoundingBox3d, TestSphere>::new();
+    let handle = added.add(spheres[7].clone());
+    let frozen = added.freeze();
+    assert_eq!(frozen.get(handle), Some(&spheres[7]));
+}
+
+// Run with `cargo test --release -- --ignored --nocapture` to compare the traversal times
+#[test]
+#[ignore]
+fn test_frozen_kd_tree_timing() {
+    use std::time::Instant;
+
+    let spheres: Vec<TestSphere> = (0..1_000_000)
+        .map(|i| {
+            let i = i as f64;
+            TestSphere {
+                center: Point3d::new_raw(
+                    (0.37 * i).sin() * 100.0,
+                    (0.73 * i).cos() * 100.0,
+                    (0.11 * i).sin() * 100.0,
+                ),
+            }
+        })
+        .collect();
+    let crays: Vec<ConstrainedRay3d> = (0..100_000)
+        .map(|i| {
+            let i = i as f64;
+            ConstrainedRay3d {
+                ray: Ray3d::new(
+                    Point3d::new_raw(-150.0, (0.29 * i).sin() * 90.0, (0.53 * i).cos() * 90.0),
+                    Point3d::new_raw(1.0, (0.17 * i).sin() * 0.3, (0.41 * i).cos() * 0.3),
+                ),
+                range: (0.0, 1000.0),
+            }
+        })
+        .collect();
+
+    let kd_tree = KdTree::<BoundingBox3d, _>::build(spheres.clone());
+    let start = Instant::now();
+    let expected: Vec<_> = crays
+        .iter()
+        .map(|cray| kd_tree.get_closest_hit_handle(&hit_test_sphere, cray))
+        .collect();
+    let tree_time = start.elapsed();
+
+    let frozen = KdTree::<BoundingBox3d, _>::build(spheres).freeze();
+    let start = Instant::now();
+    let hits: Vec<_> = crays
+        .iter()
+        .map(|cray| frozen.get_closest_hit_handle(&hit_test_sphere, cray))
+        .collect();
+    let frozen_time = start.elapsed();
+
+    assert_eq!(hits, expected);
+    println!("KdTree {:?}, FrozenKdTree {:?}", tree_time, frozen_time);
+}
@@ -0,0 +1,244 @@
+use crate::frozen_kd_tree::*;
+use crate::kd_tree::*;
+use crate::kd_tree_traits::*;
+use crate::ray_box::*;
+use std::collections::HashSet;
+use std::hash::Hash;
+
+// Points with normal.dot(point) + distance >= 0 are on the inner side
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Plane3d {
+    pub normal: Direction3d,
+    pub distance: f64,
+}
+
+impl Plane3d {
+    pub fn new(normal: Direction3d, distance: f64) -> Self {
+        Self { normal, distance }
+    }
+
+    pub fn from_point(point: &Point3d, normal: &Direction3d) -> Self {
+        let normal = normal.get_normalized();
+        Self {
+            normal,
+            distance: -normal.dot(point),
+        }
+    }
+
+    pub fn signed_distance(&self, point: &Point3d) -> f64 {
+        self.normal.dot(point) + self.distance
+    }
+
+    pub fn classify(&self, enclosure: &BoundingBox3d) -> Overlap {
+        // Only the corners furthest along and against the normal need to be checked
+        let mut inner = enclosure.u;
+        let mut outer = enclosure.v;
+        for i in 0..3 {
+            if self.normal.t[i] < 0.0 {
+                inner.t[i] = enclosure.v.t[i];
+                outer.t[i] = enclosure.u.t[i];
+            }
+        }
+
+        if self.signed_distance(&outer) < 0.0 {
+            Overlap::Outside
+        } else if self.signed_distance(&inner) < 0.0 {
+            Overlap::Partial
+        } else {
+            Overlap::Inside
+        }
+    }
+}
+
+impl<Content: KdTreeContent<BoundingBox3d>> KdTreeRegion<BoundingBox3d, Content> for Plane3d {
+    fn node_overlap(&self, enclosure: &BoundingBox3d) -> Overlap {
+        self.classify(enclosure)
+    }
+
+    fn contains(&self, content: &Content) -> bool {
+        self.classify(&content.get_bounding_box()) != Overlap::Outside
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum3d {
+    pub planes: [Plane3d; 6],
+}
+
+impl Frustum3d {
+    pub fn new(planes: [Plane3d; 6]) -> Self {
+        Self { planes }
+    }
+
+    // Field of view is the vertical opening angle in radians, aspect is width over height
+    pub fn from_camera(
+        position: &Point3d,
+        direction: &Direction3d,
+        up: &Direction3d,
+        fov: f64,
+        aspect: f64,
+        near: f64,
+        far: f64,
+    ) -> Self {
+        let forward = direction.get_normalized();
+        let right = forward.cross(up).get_normalized();
+        let up = right.cross(&forward);
+
+        let half_height = (0.5 * fov).tan();
+        let half_width = half_height * aspect;
+        let left_edge = forward - right * half_width;
+        let right_edge = forward + right * half_width;
+        let bottom_edge = forward - up * half_height;
+        let top_edge = forward + up * half_height;
+
+        Self::new([
+            Plane3d::from_point(&(position + forward * near), &forward),
+            Plane3d::from_point(&(position + forward * far), &-forward),
+            Plane3d::from_point(position, &left_edge.cross(&up)),
+            Plane3d::from_point(position, &up.cross(&right_edge)),
+            Plane3d::from_point(position, &right.cross(&bottom_edge)),
+            Plane3d::from_point(position, &top_edge.cross(&right)),
+        ])
+    }
+
+    pub fn contains(&self, point: &Point3d) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.signed_distance(point) >= 0.0)
+    }
+
+    // Conservative: boxes near the frustum's edges may be classified Partial while being outside
+    pub fn classify(&self, enclosure: &BoundingBox3d) -> Overlap {
+        let mut result = Overlap::Inside;
+        for plane in &self.planes {
+            match plane.classify(enclosure) {
+                Overlap::Outside => return Overlap::Outside,
+                Overlap::Partial => result = Overlap::Partial,
+                Overlap::Inside => (),
+            }
+        }
+        result
+    }
+}
+
+impl<Content: KdTreeContent<BoundingBox3d>> KdTreeRegion<BoundingBox3d, Content> for Frustum3d {
+    fn node_overlap(&self, enclosure: &BoundingBox3d) -> Overlap {
+        self.classify(enclosure)
+    }
+
+    fn contains(&self, content: &Content) -> bool {
+        self.classify(&content.get_bounding_box()) != Overlap::Outside
+    }
+}
+
+impl<Content: KdTreeContent<BoundingBox3d>> KdTree<BoundingBox3d, Content> {
+    pub fn get_in_frustum_handles(&self, frustum: &Frustum3d) -> Vec<KdTreeHandle> {
+        self.get_in_region_handles(frustum)
+    }
+
+    pub fn get_in_frustum(&self, frustum: &Frustum3d) -> HashSet<Content>
+    where
+        Content: Clone + Eq + Hash,
+    {
+        self.get_in_region(frustum)
+    }
+}
+
+impl<Content: KdTreeContent<BoundingBox3d>> FrozenKdTree<BoundingBox3d, Content> {
+    pub fn get_in_frustum_handles(&self, frustum: &Frustum3d) -> Vec<KdTreeHandle> {
+        self.get_in_region_handles(frustum)
+    }
+
+    pub fn get_in_frustum(&self, frustum: &Frustum3d) -> HashSet<Content>
+    where
+        Content: Clone + Eq + Hash,
+    {
+        self.get_in_region(frustum)
+    }
+}
+
+#[test]
+fn test_frustum() {
+    let frustum = Frustum3d::from_camera(
+        &Point3d::new_raw(1.0, 2.0, 3.0),
+        &Direction3d::new_raw(0.0, 0.0, -2.0),
+        &Direction3d::new_raw(0.0, 1.0, 0.0),
+        std::f64::consts::FRAC_PI_2,
+        2.0,
+        0.5,
+        10.0,
+    );
+    assert!(frustum.contains(&Point3d::new_raw(1.0, 2.0, 2.0)));
+    assert!(frustum.contains(&Point3d::new_raw(8.9, 2.0, -1.0)));
+    assert!(!frustum.contains(&Point3d::new_raw(9.1, 2.0, -1.0)));
+    assert!(frustum.contains(&Point3d::new_raw(1.0, 5.9, -1.0)));
+    assert!(!frustum.contains(&Point3d::new_raw(1.0, 6.1, -1.0)));
+    assert!(!frustum.contains(&Point3d::new_raw(1.0, 2.0, 2.9)));
+    assert!(!frustum.contains(&Point3d::new_raw(1.0, 2.0, -7.1)));
+
+    let enclosure = |u: (f64, f64, f64), v: (f64, f64, f64)| BoundingBox3d {
+        u: Point3d::new_raw(u.0, u.1, u.2),
+        v: Point3d::new_raw(v.0, v.1, v.2),
+    };
+    assert_eq!(
+        frustum.classify(&enclosure((0.0, 1.0, -2.0), (2.0, 3.0, 0.0))),
+        Overlap::Inside
+    );
+    assert_eq!(
+        frustum.classify(&enclosure((0.0, 1.0, -2.0), (20.0, 3.0, 0.0))),
+        Overlap::Partial
+    );
+    assert_eq!(
+        frustum.classify(&enclosure((0.0, 1.0, 4.0), (2.0, 3.0, 5.0))),
+        Overlap::Outside
+    );
+
+    let mut points = vec![];
+    for i in 0..30 {
+        for j in 0..30 {
+            for k in 0..10 {
+                points.push(Point3d::new_raw(
+                    0.5 * i as f64 - 6.0,
+                    0.4 * j as f64 - 4.0,
+                    -1.3 * k as f64 + 3.5,
+                ));
+            }
+        }
+    }
+    let expected: HashSet<Point3d> = points
+        .iter()
+        .filter(|point| frustum.contains(point))
+        .cloned()
+        .collect();
+    assert!(!expected.is_empty() && expected.len() < points.len());
+
+    let half_space = Plane3d::from_point(
+        &Point3d::new_raw(0.0, 0.0, -1.0),
+        &Direction3d::new_raw(1.0, 1.0, -1.0),
+    );
+    let below: HashSet<Point3d> = points
+        .iter()
+        .filter(|point| half_space.signed_distance(point) >= 0.0)
+        .cloned()
+        .collect();
+
+    let built = KdTree::build(points.clone());
+    let mut added = KdTree::<BoundingBox3d, Point3d>::new();
+    added.extend(points.iter().cloned());
+    for kd_tree in [built, added] {
+        assert_eq!(kd_tree.get_in_frustum(&frustum), expected);
+        assert_eq!(kd_tree.get_in_region(&half_space), below);
+        assert_eq!(
+            kd_tree.get_in_frustum_handles(&frustum).len(),
+            expected.len()
+        );
+
+        let handles = kd_tree.get_in_frustum_handles(&frustum);
+        let frozen = kd_tree.freeze();
+        assert_eq!(frozen.get_in_frustum(&frustum), expected);
+        assert_eq!(frozen.get_in_region(&half_space), below);
+        assert_eq!(frozen.get_in_frustum_handles(&frustum), handles);
+    }
+}
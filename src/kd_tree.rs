@@ -1,23 +1,242 @@
 use crate::kd_tree_traits::*;
+use crate::kd_tree_traversal::*;
 use crate::ray_box::*;
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::hash::Hash;
+use std::ops::ControlFlow;
 
-struct KdNode<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> {
-    enclosure: BoundingBox,
-    content: Vec<Content>,
-    children: Vec<Self>,
+const SAH_TRAVERSAL_COST: f64 = 1.0;
+
+// Bounding box and index of every content a node is built from
+pub(crate) type BuildContents<BoundingBox> = Vec<(BoundingBox, u32)>;
+
+#[cfg(test)]
+use crate::sphere::*;
+#[cfg(test)]
+use crate::test_shapes::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KdTreeSplitStrategy {
+    SurfaceAreaHeuristic,
+    Median,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KdTreeError {
+    NonFiniteBounds,
+    DegenerateBounds, // Lower bound above the upper one
+    ExceedsWorldSize,
+    TooManyContents,
+}
+
+impl std::fmt::Display for KdTreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KdTreeError::NonFiniteBounds => write!(f, "bounding box is not finite"),
+            KdTreeError::DegenerateBounds => write!(f, "bounding box is degenerate"),
+            KdTreeError::ExceedsWorldSize => {
+                write!(f, "bounding box lies outside the maximum world size")
+            }
+            KdTreeError::TooManyContents => write!(f, "content indices are exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for KdTreeError {}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KdTreeSubdivision {
+    Halving,  // Splits nodes in two, the bulk build chooses where
+    Orthants, // Splits nodes at the center in every dimension, as quadtrees and octrees do
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct KdTreeConfig<BoundingBox: BoundingBoxTrait> {
+    pub subdivision: KdTreeSubdivision,
+    pub leaf_capacity: usize, // Leaves hold at most this many contents while they can be split
+    pub max_depth: usize,
+    pub min_cell_size: f64, // Nodes smaller than this are not split any further
+    pub max_world_size: f64, // The root is not extended beyond this size
+    pub initial_root: BoundingBox,
+}
+
+impl<BoundingBox: BoundingBoxTrait> Default for KdTreeConfig<BoundingBox> {
+    fn default() -> Self {
+        KdTreeConfig {
+            subdivision: KdTreeSubdivision::Halving,
+            leaf_capacity: 4,
+            max_depth: 64,
+            min_cell_size: 1e-8,
+            max_world_size: 1e8,
+            initial_root: BoundingBox::default(),
+        }
+    }
+}
+
+// Contents are stored once in the tree, nodes and handles refer to them by index.
+// The generation of a slot changes when its content is removed, so old handles stop resolving.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KdTreeHandle {
+    pub(crate) index: u32,
+    pub(crate) generation: u32,
+}
+
+impl KdTreeHandle {
+    pub(crate) fn new(index: u32, generations: &[u32]) -> Self {
+        KdTreeHandle {
+            index,
+            generation: generations[index as usize],
+        }
+    }
+
+    pub(crate) fn resolve<'a, Content>(
+        &self,
+        contents: &'a [Option<Content>],
+        generations: &[u32],
+    ) -> Option<&'a Content> {
+        if generations.get(self.index as usize) != Some(&self.generation) {
+            return None;
+        }
+        contents.get(self.index as usize)?.as_ref()
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct KdNode<BoundingBox: BoundingBoxTrait> {
+    pub(crate) enclosure: BoundingBox,
+    pub(crate) content: Vec<u32>,
+    pub(crate) children: Vec<Self>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(try_from = "KdTreeData<BoundingBox, Content>")
+)]
 pub struct KdTree<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> {
-    root: Option<KdNode<BoundingBox, Content>>,
+    pub(crate) root: Option<KdNode<BoundingBox>>,
+    pub(crate) contents: Vec<Option<Content>>,
+    pub(crate) generations: Vec<u32>,
+    free: Vec<u32>,
+    size: usize,
+    config: KdTreeConfig<BoundingBox>,
+}
+
+// Deserialized trees are only accepted once their indices are consistent
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct KdTreeData<BoundingBox: BoundingBoxTrait, Content> {
+    root: Option<KdNode<BoundingBox>>,
+    contents: Vec<Option<Content>>,
+    generations: Vec<u32>,
+    free: Vec<u32>,
     size: usize,
+    config: KdTreeConfig<BoundingBox>,
+}
+
+#[cfg(feature = "serde")]
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    TryFrom<KdTreeData<BoundingBox, Content>> for KdTree<BoundingBox, Content>
+{
+    type Error = &'static str;
+
+    fn try_from(data: KdTreeData<BoundingBox, Content>) -> Result<Self, Self::Error> {
+        let contents = &data.contents;
+        if u32::try_from(contents.len()).is_err() || data.generations.len() < contents.len() {
+            return Err("content slots don't match their generations");
+        }
+        if data.size != contents.iter().filter(|content| content.is_some()).count() {
+            return Err("size doesn't match the contents");
+        }
+
+        let mut is_free = vec![false; contents.len()];
+        for &index in &data.free {
+            match contents.get(index as usize) {
+                Some(None) if !is_free[index as usize] => is_free[index as usize] = true,
+                _ => return Err("free list refers to an occupied or missing slot"),
+            }
+        }
+        if data.free.len() + data.size != contents.len() {
+            return Err("free list doesn't cover every empty slot");
+        }
+
+        // Every content has to be stored in the tree, and nodes may only refer to contents
+        let mut is_stored = vec![false; contents.len()];
+        let mut stack: Vec<&KdNode<BoundingBox>> = data.root.iter().collect();
+        if stack.is_empty() {
+            return Err("root is missing");
+        }
+        while let Some(node) = stack.pop() {
+            for &index in &node.content {
+                match contents.get(index as usize) {
+                    Some(Some(_)) => is_stored[index as usize] = true,
+                    _ => return Err("node refers to an empty or missing slot"),
+                }
+            }
+            stack.extend(node.children.iter());
+        }
+        if contents
+            .iter()
+            .zip(&is_stored)
+            .any(|(content, is_stored)| content.is_some() && !is_stored)
+        {
+            return Err("content isn't stored in any node");
+        }
+
+        Ok(KdTree {
+            root: data.root,
+            contents: data.contents,
+            generations: data.generations,
+            free: data.free,
+            size: data.size,
+            config: data.config,
+        })
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> Default
+    for KdTree<BoundingBox, Content>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Orders by ascending distance when used in a BinaryHeap
+struct QueueEntry<T> {
+    distance: f64,
+    item: T,
+}
+
+impl<T> PartialEq for QueueEntry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance.total_cmp(&other.distance) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for QueueEntry<T> {}
+
+impl<T> PartialOrd for QueueEntry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for QueueEntry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
 }
 
 impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
     KdTree<BoundingBox, Content>
 {
-    fn new_node(enclosure: BoundingBox) -> KdNode<BoundingBox, Content> {
-        KdNode::<BoundingBox, Content> {
+    fn new_node(enclosure: BoundingBox) -> KdNode<BoundingBox> {
+        KdNode::<BoundingBox> {
             enclosure,
             content: vec![],
             children: vec![],
@@ -25,195 +244,2076 @@ impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
     }
 
     fn add_to_node(
-        node: &mut KdNode<BoundingBox, Content>,
-        content: &Content,
+        node: &mut KdNode<BoundingBox>,
+        contents: &[Option<Content>],
+        index: u32,
         content_enclosure: &BoundingBox,
+        config: &KdTreeConfig<BoundingBox>,
+        depth: usize,
     ) {
         if content_enclosure.is_sub_scale(&node.enclosure)
             && node.children.is_empty()
-            && node.content.len() > 3
+            && node.content.len() >= config.leaf_capacity
+            && depth < config.max_depth
         {
-            if let Some((left, right)) = node.enclosure.partition() {
-                let mut left = Self::new_node(left);
-                let mut right = Self::new_node(right);
-
-                for content in &node.content {
-                    let enclosure = content.get_bounding_box();
-                    if enclosure.intersects(&left.enclosure) {
-                        left.content.push(content.clone());
-                    }
-                    if enclosure.intersects(&right.enclosure) {
-                        right.content.push(content.clone());
+            if let Some(children) = Self::subdivide(&node.enclosure, config) {
+                node.children = children.into_iter().map(Self::new_node).collect();
+
+                for &other in &node.content {
+                    let enclosure = contents[other as usize]
+                        .as_ref()
+                        .unwrap()
+                        .get_bounding_box();
+                    for child in &mut node.children {
+                        if enclosure.intersects(&child.enclosure) {
+                            child.content.push(other);
+                        }
                     }
                 }
                 node.content.clear();
-                node.children.push(left);
-                node.children.push(right);
             }
         }
 
         if node.children.is_empty() {
-            node.content.push(content.clone());
+            node.content.push(index);
         } else {
             for child in &mut node.children {
                 if content_enclosure.intersects(&child.enclosure) {
-                    Self::add_to_node(child, content, content_enclosure);
+                    Self::add_to_node(child, contents, index, content_enclosure, config, depth + 1);
+                }
+            }
+        }
+    }
+
+    fn get_diagonal(enclosure: &BoundingBox) -> f64 {
+        (0..BoundingBox::DIMENSIONS)
+            .map(|i| enclosure.get_bounds(i))
+            .map(|(u, v)| (v - u) * (v - u))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    fn subdivide(
+        enclosure: &BoundingBox,
+        config: &KdTreeConfig<BoundingBox>,
+    ) -> Option<Vec<BoundingBox>> {
+        match config.subdivision {
+            KdTreeSubdivision::Halving => enclosure
+                .partition(config.min_cell_size)
+                .map(|(left, right)| vec![left, right]),
+            KdTreeSubdivision::Orthants => {
+                if Self::get_diagonal(enclosure) < config.min_cell_size {
+                    return None;
+                }
+
+                let mut result = vec![enclosure.clone()];
+                for i in 0..BoundingBox::DIMENSIONS {
+                    let (lower, upper) = enclosure.get_bounds(i);
+                    let midpoint = 0.5 * (lower + upper);
+                    result = result
+                        .iter()
+                        .flat_map(|part| {
+                            let (left, right) = Self::split_enclosure(part, i, midpoint);
+                            [left, right]
+                        })
+                        .collect();
+                }
+                Some(result)
+            }
+        }
+    }
+
+    // Returns the new siblings of the root and the enclosure of their parent
+    fn grow(
+        enclosure: &BoundingBox,
+        config: &KdTreeConfig<BoundingBox>,
+    ) -> Option<(Vec<BoundingBox>, BoundingBox)> {
+        match config.subdivision {
+            KdTreeSubdivision::Halving => enclosure
+                .extend(config.max_world_size)
+                .map(|(other, parent)| (vec![other], parent)),
+            KdTreeSubdivision::Orthants => {
+                if Self::get_diagonal(enclosure) > config.max_world_size {
+                    return None;
+                }
+
+                // Doubles every dimension towards the origin, like BoundingBoxTrait::extend.
+                // The first part is the old root, the others share its faces exactly.
+                let mut parent = enclosure.clone();
+                let mut parts = vec![enclosure.clone()];
+                for i in 0..BoundingBox::DIMENSIONS {
+                    let (lower, upper) = enclosure.get_bounds(i);
+                    let dif = upper - lower;
+                    let added = if -lower.min(0.0) < upper.max(0.0) {
+                        parent.set_bounds(i, (lower - dif, upper));
+                        (lower - dif, lower)
+                    } else {
+                        parent.set_bounds(i, (lower, upper + dif));
+                        (upper, upper + dif)
+                    };
+                    parts = parts
+                        .iter()
+                        .flat_map(|part| {
+                            let mut other = part.clone();
+                            other.set_bounds(i, added);
+                            [part.clone(), other]
+                        })
+                        .collect();
                 }
+                parts.remove(0);
+                Some((parts, parent))
+            }
+        }
+    }
+
+    fn collect_intersecting(
+        node: &KdNode<BoundingBox>,
+        contents: &[Option<Content>],
+        filter: &BoundingBox,
+        result: &mut Vec<u32>,
+    ) {
+        if !node.enclosure.intersects(filter) {
+            return;
+        }
+
+        for &index in &node.content {
+            let enclosure = contents[index as usize]
+                .as_ref()
+                .unwrap()
+                .get_bounding_box();
+            if enclosure.intersects(filter) {
+                result.push(index);
             }
         }
+
+        for child in &node.children {
+            Self::collect_intersecting(child, contents, filter, result);
+        }
     }
 
     pub fn new() -> Self {
+        Self::with_config(KdTreeConfig::default())
+    }
+
+    pub fn with_config(config: KdTreeConfig<BoundingBox>) -> Self {
         KdTree {
-            root: Some(Self::new_node(BoundingBox::default())),
+            root: Some(Self::new_node(config.initial_root.clone())),
+            contents: vec![],
+            generations: vec![],
+            free: vec![],
             size: 0,
+            config,
+        }
+    }
+
+    pub fn get_config(&self) -> &KdTreeConfig<BoundingBox> {
+        &self.config
+    }
+
+    fn insert_content(&mut self, content: Content) -> Result<u32, KdTreeError> {
+        if let Some(index) = self.free.pop() {
+            self.contents[index as usize] = Some(content);
+            return Ok(index);
+        }
+
+        let index = u32::try_from(self.contents.len()).map_err(|_| KdTreeError::TooManyContents)?;
+        self.contents.push(Some(content));
+        // Slots emptied by clear keep their generation
+        if self.generations.len() < self.contents.len() {
+            self.generations.push(0);
+        }
+        Ok(index)
+    }
+
+    fn validate(&self, content_enclosure: &BoundingBox) -> Result<(), KdTreeError> {
+        for i in 0..BoundingBox::DIMENSIONS {
+            let (lower, upper) = content_enclosure.get_bounds(i);
+            if !lower.is_finite() || !upper.is_finite() {
+                return Err(KdTreeError::NonFiniteBounds);
+            }
+            if lower > upper {
+                return Err(KdTreeError::DegenerateBounds);
+            }
+        }
+
+        // Grow a copy of the root enclosure first, so a failing insert leaves the tree untouched
+        let mut enclosure = self.root.as_ref().unwrap().enclosure.clone();
+        while !content_enclosure.is_contained(&enclosure) {
+            enclosure = match Self::grow(&enclosure, &self.config) {
+                Some((_, parent)) => parent,
+                None => return Err(KdTreeError::ExceedsWorldSize),
+            };
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, content: Content) -> KdTreeHandle {
+        match self.try_add(content) {
+            Ok(handle) => handle,
+            Err(error) => panic!("Can't add content to KdTree: {}", error),
         }
     }
 
-    pub fn add(&mut self, content: Content) {
+    pub fn try_add(&mut self, content: Content) -> Result<KdTreeHandle, KdTreeError> {
         let content_enclosure = content.get_bounding_box();
+        self.validate(&content_enclosure)?;
+        let index = self.insert_content(content)?;
 
         while !content_enclosure.is_contained(&self.root.as_ref().unwrap().enclosure) {
-            let (other, parent) = self.root.as_ref().unwrap().enclosure.extend().unwrap();
-
-            let other_node = Self::new_node(other);
             let current_root = self.root.take().unwrap();
+            let (others, parent) = Self::grow(&current_root.enclosure, &self.config).unwrap();
             let mut new_root = Self::new_node(parent);
-            new_root.children.push(other_node);
+
+            for other in others {
+                let mut other_node = Self::new_node(other);
+
+                // Every leaf has to hold all contents touching it, including those on shared faces
+                let mut touching = vec![];
+                Self::collect_intersecting(
+                    &current_root,
+                    &self.contents,
+                    &other_node.enclosure,
+                    &mut touching,
+                );
+                touching.sort_unstable();
+                touching.dedup();
+                for index in touching {
+                    let enclosure = self.contents[index as usize]
+                        .as_ref()
+                        .unwrap()
+                        .get_bounding_box();
+                    Self::add_to_node(
+                        &mut other_node,
+                        &self.contents,
+                        index,
+                        &enclosure,
+                        &self.config,
+                        1,
+                    );
+                }
+                new_root.children.push(other_node);
+            }
             new_root.children.push(current_root);
 
             self.root = Some(new_root);
         }
 
         Self::add_to_node(
-            &mut self.root.as_mut().unwrap(),
-            &content,
+            self.root.as_mut().unwrap(),
+            &self.contents,
+            index,
             &content_enclosure,
+            &self.config,
+            0,
         );
         self.size += 1;
+        Ok(KdTreeHandle::new(index, &self.generations))
     }
 
-    fn get_intersection_internal(
-        node: &KdNode<BoundingBox, Content>,
-        filter: &BoundingBox,
-        result: &mut HashSet<Content>,
-    ) {
-        if !node.enclosure.intersects(filter) {
-            return;
-        }
+    pub fn get(&self, handle: KdTreeHandle) -> Option<&Content> {
+        handle.resolve(&self.contents, &self.generations)
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
 
-        for content in &node.content {
-            if content.get_bounding_box().intersects(filter) {
-                result.insert(content.clone());
+    // Removes all contents but keeps the configuration
+    pub fn clear(&mut self) {
+        self.root = Some(Self::new_node(self.config.initial_root.clone()));
+        for (content, generation) in self.contents.iter().zip(&mut self.generations) {
+            if content.is_some() {
+                *generation = generation.wrapping_add(1);
             }
         }
+        self.contents.clear();
+        self.free.clear();
+        self.size = 0;
+    }
 
-        for child in &node.children {
-            Self::get_intersection_internal(child, filter, result);
+    pub fn iter(&self) -> KdTreeIterator<'_, Content> {
+        KdTreeIterator {
+            contents: self.contents.iter(),
         }
     }
 
-    pub fn get_intersection(&self, filter: &BoundingBox) -> HashSet<Content> {
-        let mut result = HashSet::new();
+    fn split_enclosure(
+        enclosure: &BoundingBox,
+        dimension: usize,
+        position: f64,
+    ) -> (BoundingBox, BoundingBox) {
+        let (lower, upper) = enclosure.get_bounds(dimension);
+        let mut left = enclosure.clone();
+        let mut right = enclosure.clone();
+        left.set_bounds(dimension, (lower, position));
+        right.set_bounds(dimension, (position, upper));
+        (left, right)
+    }
 
-        if let Some(node) = &self.root {
-            Self::get_intersection_internal(node, filter, &mut result);
-        }
+    fn find_median_split(
+        enclosure: &BoundingBox,
+        contents: &[(BoundingBox, u32)],
+    ) -> Option<(usize, f64)> {
+        let dimension = (0..BoundingBox::DIMENSIONS)
+            .map(|i| (i, enclosure.get_bounds(i)))
+            .max_by(|(_, a), (_, b)| (a.1 - a.0).total_cmp(&(b.1 - b.0)))?
+            .0;
+        let (lower, upper) = enclosure.get_bounds(dimension);
 
-        result
+        let mut centers: Vec<f64> = contents
+            .iter()
+            .map(|(enclosure, _)| {
+                let (u, v) = enclosure.get_bounds(dimension);
+                0.5 * (u + v)
+            })
+            .collect();
+        centers.sort_by(|a, b| a.total_cmp(b));
+
+        let median = centers[centers.len() / 2];
+        if lower < median && median < upper {
+            Some((dimension, median))
+        } else {
+            Some((dimension, 0.5 * (lower + upper)))
+        }
     }
-}
 
-impl<BoundingBox: HittableBoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
-    KdTree<BoundingBox, Content>
-{
-    fn get_closest_hit_internal<F>(
-        node: &KdNode<BoundingBox, Content>,
-        fun: &F,
-        cray: &ConstrainedRay3d,
-        result: &mut Option<Content>,
-        current: &mut f64,
-    ) where
-        F: Fn(&Content, &ConstrainedRay3d) -> Option<f64>,
-    {
-        for content in &node.content {
-            if let Some(candidate) = fun(content, cray) {
-                if candidate < *current {
-                    *current = candidate;
-                    *result = Some(content.clone());
-                }
-            }
+    fn find_sah_split(
+        enclosure: &BoundingBox,
+        contents: &[(BoundingBox, u32)],
+    ) -> Option<(usize, f64)> {
+        let area = enclosure.get_surface_area();
+        if area <= 0.0 {
+            return None;
         }
 
-        for children in &node.children {
-            match children.enclosure.hit(cray) {
-                HitBoxResult::Miss => (),
-                HitBoxResult::Inside(_) => {
-                    Self::get_closest_hit_internal::<F>(children, fun, cray, result, current)
+        // Not splitting at all costs one intersection test per content
+        let mut best_cost = contents.len() as f64;
+        let mut best = None;
+
+        for dimension in 0..BoundingBox::DIMENSIONS {
+            let (lower, upper) = enclosure.get_bounds(dimension);
+            let mut mins: Vec<f64> = contents
+                .iter()
+                .map(|(enclosure, _)| enclosure.get_bounds(dimension).0)
+                .collect();
+            let mut maxs: Vec<f64> = contents
+                .iter()
+                .map(|(enclosure, _)| enclosure.get_bounds(dimension).1)
+                .collect();
+            mins.sort_by(|a, b| a.total_cmp(b));
+            maxs.sort_by(|a, b| a.total_cmp(b));
+
+            let mut events: Vec<f64> = mins
+                .iter()
+                .chain(maxs.iter())
+                .cloned()
+                .filter(|&position| lower < position && position < upper)
+                .chain([lower, upper])
+                .collect();
+            events.sort_by(|a, b| a.total_cmp(b));
+            events.dedup();
+
+            // Contents touching a plane end up on both sides, so the planes sit between events
+            for position in events.windows(2).map(|pair| 0.5 * (pair[0] + pair[1])) {
+                let left_count = mins.partition_point(|&min| min <= position);
+                let right_count = maxs.len() - maxs.partition_point(|&max| max < position);
+                // Cutting off empty space alone would never stop around degenerate contents
+                if left_count == 0 || right_count == 0 {
+                    continue;
                 }
-                HitBoxResult::Outside(close, _) => {
-                    if close <= *current {
-                        Self::get_closest_hit_internal::<F>(children, fun, cray, result, current);
-                    }
+                let (left, right) = Self::split_enclosure(enclosure, dimension, position);
+
+                let cost = SAH_TRAVERSAL_COST
+                    + (left.get_surface_area() * left_count as f64
+                        + right.get_surface_area() * right_count as f64)
+                        / area;
+                if cost < best_cost {
+                    best_cost = cost;
+                    best = Some((dimension, position));
                 }
             }
         }
+
+        best
+    }
+
+    // Returns the node with its contents if it stays a leaf, otherwise its children to build
+    pub(crate) fn split_node(
+        enclosure: BoundingBox,
+        contents: BuildContents<BoundingBox>,
+        strategy: Option<KdTreeSplitStrategy>,
+        config: &KdTreeConfig<BoundingBox>,
+        depth: usize,
+    ) -> (
+        KdNode<BoundingBox>,
+        Vec<(BoundingBox, BuildContents<BoundingBox>)>,
+    ) {
+        let mut node = Self::new_node(enclosure);
+
+        let split = if contents.len() <= config.leaf_capacity || depth >= config.max_depth {
+            None
+        } else if config.subdivision == KdTreeSubdivision::Orthants {
+            // Like add, only nodes larger than some of their contents are split
+            if contents
+                .iter()
+                .any(|(content_enclosure, _)| content_enclosure.is_sub_scale(&node.enclosure))
+            {
+                Self::subdivide(&node.enclosure, config)
+            } else {
+                None
+            }
+        } else if node.enclosure.partition(config.min_cell_size).is_none() {
+            None
+        } else {
+            // Without a strategy there is no way to place a halving split
+            strategy
+                .and_then(|strategy| match strategy {
+                    KdTreeSplitStrategy::SurfaceAreaHeuristic => {
+                        Self::find_sah_split(&node.enclosure, &contents)
+                    }
+                    KdTreeSplitStrategy::Median => {
+                        Self::find_median_split(&node.enclosure, &contents)
+                    }
+                })
+                .map(|(dimension, position)| {
+                    let (left, right) = Self::split_enclosure(&node.enclosure, dimension, position);
+                    vec![left, right]
+                })
+        };
+
+        let children: Vec<(BoundingBox, BuildContents<BoundingBox>)> = match split {
+            Some(split) => split
+                .into_iter()
+                .map(|child| {
+                    let child_contents = contents
+                        .iter()
+                        .filter(|(content_enclosure, _)| content_enclosure.intersects(&child))
+                        .cloned()
+                        .collect();
+                    (child, child_contents)
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        if children
+            .iter()
+            .all(|(_, child_contents)| child_contents.len() == contents.len())
+        {
+            node.content = contents.into_iter().map(|(_, index)| index).collect();
+            return (node, vec![]);
+        }
+        (node, children)
+    }
+
+    pub(crate) fn build_node(
+        enclosure: BoundingBox,
+        contents: BuildContents<BoundingBox>,
+        strategy: Option<KdTreeSplitStrategy>,
+        config: &KdTreeConfig<BoundingBox>,
+        depth: usize,
+    ) -> KdNode<BoundingBox> {
+        let (mut node, children) = Self::split_node(enclosure, contents, strategy, config, depth);
+        node.children = children
+            .into_iter()
+            .map(|(child, child_contents)| {
+                Self::build_node(child, child_contents, strategy, config, depth + 1)
+            })
+            .collect();
+        node
+    }
+
+    pub fn build(contents: Vec<Content>) -> Self {
+        Self::build_with_strategy(contents, KdTreeSplitStrategy::SurfaceAreaHeuristic)
     }
 
-    pub fn get_closest_hit<F>(&self, fun: &F, cray: &ConstrainedRay3d) -> Option<Content>
+    pub fn build_with_strategy(contents: Vec<Content>, strategy: KdTreeSplitStrategy) -> Self {
+        Self::build_with_config(contents, strategy, KdTreeConfig::default())
+    }
+
+    pub fn build_with_config(
+        contents: Vec<Content>,
+        strategy: KdTreeSplitStrategy,
+        config: KdTreeConfig<BoundingBox>,
+    ) -> Self {
+        Self::build_with_root(contents, config, |enclosure, enclosures, config| {
+            Self::build_node(enclosure, enclosures, Some(strategy), config, 0)
+        })
+    }
+
+    // Orthant splits are always at the center, so no split strategy is involved
+    pub(crate) fn build_orthants(
+        contents: Vec<Content>,
+        config: KdTreeConfig<BoundingBox>,
+    ) -> Self {
+        let config = KdTreeConfig {
+            subdivision: KdTreeSubdivision::Orthants,
+            ..config
+        };
+        Self::build_with_root(contents, config, |enclosure, enclosures, config| {
+            Self::build_node(enclosure, enclosures, None, config, 0)
+        })
+    }
+
+    // Sets up the root enclosure and contents, `build_root` builds the nodes below
+    pub(crate) fn build_with_root<F>(
+        contents: Vec<Content>,
+        config: KdTreeConfig<BoundingBox>,
+        build_root: F,
+    ) -> Self
     where
-        F: Fn(&Content, &ConstrainedRay3d) -> Option<f64>,
+        F: FnOnce(
+            BoundingBox,
+            BuildContents<BoundingBox>,
+            &KdTreeConfig<BoundingBox>,
+        ) -> KdNode<BoundingBox>,
     {
-        let mut result: Option<Content> = None;
-        let mut current = cray.range.1;
-        Self::get_closest_hit_internal::<F>(
-            &self.root.as_ref().unwrap(),
-            fun,
-            cray,
-            &mut result,
-            &mut current,
-        );
+        if u32::try_from(contents.len()).is_err() {
+            panic!("Can't build KdTree: {}", KdTreeError::TooManyContents);
+        }
+        let enclosures: BuildContents<BoundingBox> = contents
+            .iter()
+            .enumerate()
+            .map(|(index, content)| (content.get_bounding_box(), index as u32))
+            .collect();
 
-        result
+        let mut enclosure = match enclosures.split_first() {
+            Some(((first, _), rest)) => rest.iter().fold(first.clone(), |enclosure, (other, _)| {
+                enclosure.union(other)
+            }),
+            None => return Self::with_config(config),
+        };
+
+        // The root needs an extent in every dimension, otherwise it can't be extended later
+        let size = (0..BoundingBox::DIMENSIONS)
+            .map(|i| enclosure.get_bounds(i))
+            .map(|(u, v)| v - u)
+            .fold(0.0, f64::max);
+        let size = if size > 0.0 { size } else { 1.0 };
+        for i in 0..BoundingBox::DIMENSIONS {
+            let (u, v) = enclosure.get_bounds(i);
+            if v - u <= 0.0 {
+                enclosure.set_bounds(i, (u - 0.5 * size, v + 0.5 * size));
+            }
+        }
+
+        KdTree {
+            root: Some(build_root(enclosure, enclosures, &config)),
+            size: contents.len(),
+            generations: vec![0; contents.len()],
+            contents: contents.into_iter().map(Some).collect(),
+            free: vec![],
+            config,
+        }
     }
-}
 
-#[test]
-fn test_kd_tree_with_points_2d() {
-    let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::new();
+    fn merge_children(node: &mut KdNode<BoundingBox>, leaf_capacity: usize) {
+        if node.children.is_empty() || node.children.iter().any(|c| !c.children.is_empty()) {
+            return;
+        }
 
-    kd_tree.add(Point2d::new_raw(0.0, 3.0));
-    kd_tree.add(Point2d::new_raw(1.0, 12.0));
-    kd_tree.add(Point2d::new_raw(5.0, 2.0));
-    kd_tree.add(Point2d::new_raw(-3.0, 2.0));
-    kd_tree.add(Point2d::new_raw(3.3, -1.0));
-    kd_tree.add(Point2d::new_raw(3.3, 3.0));
-    assert_eq!(kd_tree.size, 6);
+        // Contents straddling the split are in several children but must be kept only once
+        let mut merged: Vec<u32> = vec![];
+        for child in &node.children {
+            for index in &child.content {
+                if !merged.contains(index) {
+                    merged.push(*index);
+                }
+            }
+        }
+
+        if merged.len() <= leaf_capacity {
+            node.children.clear();
+            node.content = merged;
+        }
+    }
+
+    fn remove_from_node(
+        node: &mut KdNode<BoundingBox>,
+        index: u32,
+        content_enclosure: &BoundingBox,
+        leaf_capacity: usize,
+    ) -> bool {
+        if !content_enclosure.intersects(&node.enclosure) {
+            return false;
+        }
+
+        let mut removed = false;
+        if let Some(position) = node.content.iter().position(|&other| other == index) {
+            node.content.swap_remove(position);
+            removed = true;
+        }
+
+        for child in &mut node.children {
+            removed |= Self::remove_from_node(child, index, content_enclosure, leaf_capacity);
+        }
+
+        if removed {
+            Self::merge_children(node, leaf_capacity);
+        }
+        removed
+    }
+
+    pub fn remove_handle(&mut self, handle: KdTreeHandle) -> Option<Content> {
+        let content_enclosure = self.get(handle)?.get_bounding_box();
 
+        if let Some(node) = &mut self.root {
+            Self::remove_from_node(
+                node,
+                handle.index,
+                &content_enclosure,
+                self.config.leaf_capacity,
+            );
+        }
+
+        let generation = &mut self.generations[handle.index as usize];
+        *generation = generation.wrapping_add(1);
+        self.free.push(handle.index);
+        self.size -= 1;
+        self.contents[handle.index as usize].take()
+    }
+
+    fn find_internal(
+        &self,
+        node: &KdNode<BoundingBox>,
+        content: &Content,
+        content_enclosure: &BoundingBox,
+    ) -> Option<u32>
+    where
+        Content: PartialEq,
     {
-        let result = kd_tree.get_intersection(&BoundingBox2d {
-            u: Point2d::new_raw(0.0, 0.0),
-            v: Point2d::new_raw(4.0, 4.0),
-        });
-        println!("{:?}", result);
-        assert_eq!(result.len(), 2);
+        if !content_enclosure.intersects(&node.enclosure) {
+            return None;
+        }
+
+        if let Some(&index) = node
+            .content
+            .iter()
+            .find(|&&index| self.get_content(index) == content)
+        {
+            return Some(index);
+        }
+
+        node.children
+            .iter()
+            .find_map(|child| self.find_internal(child, content, content_enclosure))
     }
 
+    pub fn find(&self, content: &Content) -> Option<KdTreeHandle>
+    where
+        Content: PartialEq,
     {
-        let result = kd_tree.get_intersection(&BoundingBox2d {
-            u: Point2d::new_raw(2.0, 2.0),
-            v: Point2d::new_raw(6.0, 6.0),
-        });
-        println!("{:?}", result);
-        assert_eq!(result.len(), 2);
+        let node = self.root.as_ref()?;
+        self.find_internal(node, content, &content.get_bounding_box())
+            .map(|index| KdTreeHandle::new(index, &self.generations))
     }
 
+    pub fn remove(&mut self, content: &Content) -> bool
+    where
+        Content: PartialEq,
     {
-        let result = kd_tree.get_intersection(&BoundingBox2d {
-            u: Point2d::new_raw(-6.0, 1.0),
-            v: Point2d::new_raw(6.0, 4.0),
-        });
-        println!("{:?}", result);
-        assert_eq!(result.len(), 4);
+        match self.find(content) {
+            Some(handle) => self.remove_handle(handle).is_some(),
+            None => false,
+        }
+    }
+
+    // Replaces `old` with `new` and returns whether `old` was found. Nothing changes when
+    // `old` is missing or `new` can't be inserted.
+    pub fn update(&mut self, old: &Content, new: Content) -> Result<bool, KdTreeError>
+    where
+        Content: PartialEq,
+    {
+        self.validate(&new.get_bounding_box())?;
+        let Some(handle) = self.find(old) else {
+            return Ok(false);
+        };
+        self.remove_handle(handle);
+        self.try_add(new)?;
+        Ok(true)
+    }
+
+    // Contents straddling a split are reported only by the leaf that holds the lower corner
+    // of their overlap with the filter. Leaves are treated as half-open, except at the upper
+    // faces of the root, so exactly one leaf qualifies.
+    pub(crate) fn is_reference_node(
+        enclosure: &BoundingBox,
+        root_enclosure: &BoundingBox,
+        content_enclosure: &BoundingBox,
+        filter: &BoundingBox,
+    ) -> bool {
+        (0..BoundingBox::DIMENSIONS).all(|i| {
+            let (lower, upper) = enclosure.get_bounds(i);
+            let point = content_enclosure
+                .get_bounds(i)
+                .0
+                .max(filter.get_bounds(i).0);
+            lower <= point && (point < upper || upper >= root_enclosure.get_bounds(i).1)
+        })
+    }
+
+    // Visits every content intersecting `filter` once, without cloning or hashing
+    pub fn query_with<B, F>(&self, filter: &BoundingBox, mut fun: F) -> ControlFlow<B>
+    where
+        F: FnMut(&Content) -> ControlFlow<B>,
+    {
+        self.query(filter, |_, content| fun(content))
+    }
+
+    pub fn get_intersection_handles(&self, filter: &BoundingBox) -> Vec<KdTreeHandle> {
+        let mut result = vec![];
+        let _: ControlFlow<()> = self.query(filter, |index, _| {
+            result.push(KdTreeHandle::new(index, &self.generations));
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    pub fn get_intersection(&self, filter: &BoundingBox) -> HashSet<Content>
+    where
+        Content: Clone + Eq + Hash,
+    {
+        let mut result = HashSet::new();
+        let _: ControlFlow<()> = self.query(filter, |_, content| {
+            result.insert(content.clone());
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    pub fn get_in_region_handles<R>(&self, region: &R) -> Vec<KdTreeHandle>
+    where
+        R: KdTreeRegion<BoundingBox, Content>,
+    {
+        self.get_in_region_indices(region)
+            .into_iter()
+            .map(|index| KdTreeHandle::new(index, &self.generations))
+            .collect()
+    }
+
+    pub fn get_in_region<R>(&self, region: &R) -> HashSet<Content>
+    where
+        R: KdTreeRegion<BoundingBox, Content>,
+        Content: Clone + Eq + Hash,
+    {
+        self.get_in_region_indices(region)
+            .into_iter()
+            .map(|index| self.get_content(index).clone())
+            .collect()
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    KdTreeNodes<BoundingBox, Content> for KdTree<BoundingBox, Content>
+{
+    type Node<'a>
+        = &'a KdNode<BoundingBox>
+    where
+        Self: 'a;
+
+    fn get_root(&self) -> Option<Self::Node<'_>> {
+        self.root.as_ref()
+    }
+
+    fn get_enclosure<'a>(&'a self, node: Self::Node<'a>) -> &'a BoundingBox {
+        &node.enclosure
+    }
+
+    fn get_node_content<'a>(&'a self, node: Self::Node<'a>) -> &'a [u32] {
+        &node.content
+    }
+
+    fn get_child_count<'a>(&'a self, node: Self::Node<'a>) -> usize {
+        node.children.len()
+    }
+
+    fn get_child<'a>(&'a self, node: Self::Node<'a>, i: usize) -> Self::Node<'a> {
+        &node.children[i]
+    }
+
+    fn get_content(&self, index: u32) -> &Content {
+        self.contents[index as usize].as_ref().unwrap()
+    }
+}
+
+// Yields every content once, in no particular order
+pub struct KdTreeIterator<'a, Content> {
+    contents: std::slice::Iter<'a, Option<Content>>,
+}
+
+impl<'a, Content> Iterator for KdTreeIterator<'a, Content> {
+    type Item = &'a Content;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.contents.find_map(|content| content.as_ref())
+    }
+}
+
+pub struct KdTreeIntoIterator<Content> {
+    contents: std::vec::IntoIter<Option<Content>>,
+}
+
+impl<Content> Iterator for KdTreeIntoIterator<Content> {
+    type Item = Content;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.contents.find_map(|content| content)
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> IntoIterator
+    for KdTree<BoundingBox, Content>
+{
+    type Item = Content;
+    type IntoIter = KdTreeIntoIterator<Content>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        KdTreeIntoIterator {
+            contents: self.contents.into_iter(),
+        }
+    }
+}
+
+impl<'a, BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> IntoIterator
+    for &'a KdTree<BoundingBox, Content>
+{
+    type Item = &'a Content;
+    type IntoIter = KdTreeIterator<'a, Content>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Collecting builds the tree in bulk, which gives a better tree than adding one by one
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> FromIterator<Content>
+    for KdTree<BoundingBox, Content>
+{
+    fn from_iter<I: IntoIterator<Item = Content>>(iter: I) -> Self {
+        Self::build(iter.into_iter().collect())
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> Extend<Content>
+    for KdTree<BoundingBox, Content>
+{
+    fn extend<I: IntoIterator<Item = Content>>(&mut self, iter: I) {
+        for content in iter {
+            self.add(content);
+        }
+    }
+}
+
+impl<BoundingBox: HittableBoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    KdTree<BoundingBox, Content>
+{
+    pub fn get_closest_hit_handle<F>(
+        &self,
+        fun: &F,
+        cray: &BoundingBox::Ray,
+    ) -> Option<KdTreeHandle>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+    {
+        self.get_closest_hit_index(fun, cray)
+            .map(|index| KdTreeHandle::new(index, &self.generations))
+    }
+
+    pub fn get_closest_hit<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Option<Content>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+        Content: Clone,
+    {
+        self.get_closest_hit_handle(fun, cray)
+            .map(|handle| self.get_content(handle.index).clone())
+    }
+
+    // Lazily yields every hit along the ray front to back, each content only once
+    pub fn get_hit_iterator<'a, F>(
+        &'a self,
+        fun: &'a F,
+        cray: &'a BoundingBox::Ray,
+    ) -> KdTreeHitIterator<'a, BoundingBox, Content, F>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+    {
+        let mut iterator = KdTreeHitIterator {
+            fun,
+            cray,
+            contents: &self.contents,
+            generations: &self.generations,
+            nodes: BinaryHeap::new(),
+            hits: BinaryHeap::new(),
+            seen: HashSet::new(),
+        };
+
+        if let Some(node) = &self.root {
+            iterator.push_node(node);
+        }
+        iterator
+    }
+
+    pub fn get_all_hits<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Vec<(f64, Content)>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+        Content: Clone,
+    {
+        self.get_hit_iterator(fun, cray)
+            .map(|(t, handle)| (t, self.get_content(handle.index).clone()))
+            .collect()
+    }
+
+    pub fn get_any_hit_handle<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Option<KdTreeHandle>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+    {
+        self.get_any_hit_index(fun, cray)
+            .map(|index| KdTreeHandle::new(index, &self.generations))
+    }
+
+    pub fn get_any_hit<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Option<Content>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+        Content: Clone,
+    {
+        self.get_any_hit_handle(fun, cray)
+            .map(|handle| self.get_content(handle.index).clone())
+    }
+}
+
+pub struct KdTreeHitIterator<'a, BoundingBox, Content, F>
+where
+    BoundingBox: HittableBoundingBoxTrait,
+    Content: KdTreeContent<BoundingBox>,
+    F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+{
+    fun: &'a F,
+    cray: &'a BoundingBox::Ray,
+    contents: &'a [Option<Content>],
+    generations: &'a [u32],
+    nodes: BinaryHeap<QueueEntry<&'a KdNode<BoundingBox>>>,
+    hits: BinaryHeap<QueueEntry<u32>>,
+    seen: HashSet<u32>,
+}
+
+impl<'a, BoundingBox, Content, F> KdTreeHitIterator<'a, BoundingBox, Content, F>
+where
+    BoundingBox: HittableBoundingBoxTrait,
+    Content: KdTreeContent<BoundingBox>,
+    F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+{
+    fn push_node(&mut self, node: &'a KdNode<BoundingBox>) {
+        match node.enclosure.hit(self.cray) {
+            HitBoxResult::Miss => (),
+            HitBoxResult::Inside(_) => self.nodes.push(QueueEntry {
+                distance: self.cray.get_range().0,
+                item: node,
+            }),
+            HitBoxResult::Outside(close, _) => self.nodes.push(QueueEntry {
+                distance: close,
+                item: node,
+            }),
+        }
+    }
+}
+
+impl<'a, BoundingBox, Content, F> Iterator for KdTreeHitIterator<'a, BoundingBox, Content, F>
+where
+    BoundingBox: HittableBoundingBoxTrait,
+    Content: KdTreeContent<BoundingBox>,
+    F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+{
+    type Item = (f64, KdTreeHandle);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // A hit is final once no unvisited node is entered before it
+            if let Some(hit) = self.hits.peek() {
+                let is_final = match self.nodes.peek() {
+                    Some(node) => hit.distance <= node.distance,
+                    None => true,
+                };
+                if is_final {
+                    let hit = self.hits.pop().unwrap();
+                    return Some((hit.distance, KdTreeHandle::new(hit.item, self.generations)));
+                }
+            }
+
+            let node = self.nodes.pop()?.item;
+            for &index in &node.content {
+                let content = self.contents[index as usize].as_ref().unwrap();
+                if let Some(candidate) = (self.fun)(content, self.cray) {
+                    if self.cray.get_range().0 <= candidate
+                        && candidate <= self.cray.get_range().1
+                        && self.seen.insert(index)
+                    {
+                        self.hits.push(QueueEntry {
+                            distance: candidate,
+                            item: index,
+                        });
+                    }
+                }
+            }
+            for child in &node.children {
+                self.push_node(child);
+            }
+        }
+    }
+}
+
+impl<BoundingBox: DistanceBoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    KdTree<BoundingBox, Content>
+{
+    fn get_k_nearest_internal<F>(
+        &self,
+        node: &KdNode<BoundingBox>,
+        fun: &F,
+        point: &BoundingBox::Point,
+        k: usize,
+        result: &mut Vec<(f64, u32)>,
+    ) where
+        F: Fn(&Content, &BoundingBox::Point) -> f64,
+    {
+        for &index in &node.content {
+            let distance = fun(self.get_content(index), point);
+            if result.len() == k && distance >= result[k - 1].0 {
+                continue;
+            }
+            // Contents straddling a split are stored in several nodes
+            if result.iter().any(|&(_, other)| other == index) {
+                continue;
+            }
+
+            let position = result.partition_point(|(other, _)| *other <= distance);
+            result.insert(position, (distance, index));
+            result.truncate(k);
+        }
+
+        let mut children: Vec<(f64, &KdNode<BoundingBox>)> = node
+            .children
+            .iter()
+            .map(|child| (child.enclosure.distance(point), child))
+            .collect();
+        children.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        for (distance, child) in children {
+            if result.len() == k && distance > result[k - 1].0 {
+                break;
+            }
+            self.get_k_nearest_internal::<F>(child, fun, point, k, result);
+        }
+    }
+
+    // `fun` must never be smaller than the distance to the bounding box of the content
+    pub fn get_k_nearest_handles_by<F>(
+        &self,
+        fun: &F,
+        point: &BoundingBox::Point,
+        k: usize,
+    ) -> Vec<KdTreeHandle>
+    where
+        F: Fn(&Content, &BoundingBox::Point) -> f64,
+    {
+        let mut result = vec![];
+
+        if let Some(node) = &self.root {
+            if k > 0 {
+                self.get_k_nearest_internal::<F>(node, fun, point, k, &mut result);
+            }
+        }
+
+        result
+            .into_iter()
+            .map(|(_, index)| KdTreeHandle::new(index, &self.generations))
+            .collect()
+    }
+
+    pub fn get_k_nearest_by<F>(&self, fun: &F, point: &BoundingBox::Point, k: usize) -> Vec<Content>
+    where
+        F: Fn(&Content, &BoundingBox::Point) -> f64,
+        Content: Clone,
+    {
+        self.get_k_nearest_handles_by(fun, point, k)
+            .into_iter()
+            .map(|handle| self.get_content(handle.index).clone())
+            .collect()
+    }
+
+    pub fn get_nearest_by<F>(&self, fun: &F, point: &BoundingBox::Point) -> Option<Content>
+    where
+        F: Fn(&Content, &BoundingBox::Point) -> f64,
+        Content: Clone,
+    {
+        self.get_k_nearest_by(fun, point, 1).pop()
+    }
+
+    fn get_within_radius_internal<F>(
+        &self,
+        node: &KdNode<BoundingBox>,
+        fun: &F,
+        point: &BoundingBox::Point,
+        radius: f64,
+        result: &mut Vec<u32>,
+    ) where
+        F: Fn(&Content, &BoundingBox::Point) -> f64,
+    {
+        if node.enclosure.distance(point) > radius {
+            return;
+        }
+
+        for &index in &node.content {
+            if fun(self.get_content(index), point) <= radius {
+                result.push(index);
+            }
+        }
+
+        for child in &node.children {
+            self.get_within_radius_internal::<F>(child, fun, point, radius, result);
+        }
+    }
+
+    pub fn get_within_radius_handles_by<F>(
+        &self,
+        fun: &F,
+        point: &BoundingBox::Point,
+        radius: f64,
+    ) -> Vec<KdTreeHandle>
+    where
+        F: Fn(&Content, &BoundingBox::Point) -> f64,
+    {
+        let mut result = vec![];
+
+        if let Some(node) = &self.root {
+            self.get_within_radius_internal::<F>(node, fun, point, radius, &mut result);
+        }
+
+        result.sort_unstable();
+        result.dedup();
+        result
+            .into_iter()
+            .map(|index| KdTreeHandle::new(index, &self.generations))
+            .collect()
+    }
+
+    pub fn get_within_radius_by<F>(
+        &self,
+        fun: &F,
+        point: &BoundingBox::Point,
+        radius: f64,
+    ) -> HashSet<Content>
+    where
+        F: Fn(&Content, &BoundingBox::Point) -> f64,
+        Content: Clone + Eq + Hash,
+    {
+        self.get_within_radius_handles_by(fun, point, radius)
+            .into_iter()
+            .map(|handle| self.get_content(handle.index).clone())
+            .collect()
+    }
+
+    pub fn get_within_radius(&self, point: &BoundingBox::Point, radius: f64) -> HashSet<Content>
+    where
+        Content: Clone + Eq + Hash,
+    {
+        self.get_within_radius_by(
+            &|content: &Content, point: &BoundingBox::Point| {
+                content.get_bounding_box().distance(point)
+            },
+            point,
+            radius,
+        )
+    }
+
+    pub fn k_nearest(&self, point: &BoundingBox::Point, k: usize) -> Vec<Content>
+    where
+        Content: Clone,
+    {
+        self.get_k_nearest_by(
+            &|content: &Content, point: &BoundingBox::Point| {
+                content.get_bounding_box().distance(point)
+            },
+            point,
+            k,
+        )
+    }
+
+    pub fn nearest(&self, point: &BoundingBox::Point) -> Option<Content>
+    where
+        Content: Clone,
+    {
+        self.k_nearest(point, 1).pop()
+    }
+}
+
+#[test]
+fn test_kd_tree_with_points_2d() {
+    let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::new();
+
+    kd_tree.add(Point2d::new_raw(0.0, 3.0));
+    kd_tree.add(Point2d::new_raw(1.0, 12.0));
+    kd_tree.add(Point2d::new_raw(5.0, 2.0));
+    kd_tree.add(Point2d::new_raw(-3.0, 2.0));
+    kd_tree.add(Point2d::new_raw(3.3, -1.0));
+    kd_tree.add(Point2d::new_raw(3.3, 3.0));
+    assert_eq!(kd_tree.size, 6);
+
+    {
+        let result = kd_tree.get_intersection(&BoundingBox2d {
+            u: Point2d::new_raw(0.0, 0.0),
+            v: Point2d::new_raw(4.0, 4.0),
+        });
+        println!("{:?}", result);
+        assert_eq!(result.len(), 2);
+    }
+
+    {
+        let result = kd_tree.get_intersection(&BoundingBox2d {
+            u: Point2d::new_raw(2.0, 2.0),
+            v: Point2d::new_raw(6.0, 6.0),
+        });
+        println!("{:?}", result);
+        assert_eq!(result.len(), 2);
+    }
+
+    {
+        let result = kd_tree.get_intersection(&BoundingBox2d {
+            u: Point2d::new_raw(-6.0, 1.0),
+            v: Point2d::new_raw(6.0, 4.0),
+        });
+        println!("{:?}", result);
+        assert_eq!(result.len(), 4);
+    }
+}
+
+#[test]
+fn test_kd_tree_nearest_2d() {
+    use crate::traits::Norm;
+
+    let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::new();
+    let mut points = vec![];
+
+    for i in 0..20 {
+        for j in 0..20 {
+            let point = Point2d::new_raw(0.37 * i as f64 - 2.0, 0.53 * j as f64 - 4.0);
+            kd_tree.add(point);
+            points.push(point);
+        }
+    }
+
+    let query = Point2d::new_raw(0.1, 0.2);
+    points.sort_by(|a, b| (a - query).length().total_cmp(&(b - query).length()));
+
+    assert_eq!(kd_tree.nearest(&query), Some(points[0]));
+    assert_eq!(kd_tree.k_nearest(&query, 7), points[0..7].to_vec());
+    assert_eq!(kd_tree.k_nearest(&query, 0), vec![]);
+    assert_eq!(kd_tree.k_nearest(&query, 1000).len(), 400);
+}
+
+#[test]
+fn test_kd_tree_nearest_by_3d() {
+    use crate::traits::Norm;
+
+    let mut kd_tree = KdTree::<BoundingBox3d, Point3d>::new();
+
+    kd_tree.add(Point3d::new_raw(0.0, 0.0, 0.0));
+    kd_tree.add(Point3d::new_raw(1.0, 2.0, 0.0));
+    kd_tree.add(Point3d::new_raw(-3.0, 0.5, 1.0));
+    kd_tree.add(Point3d::new_raw(4.0, 4.0, 4.0));
+    kd_tree.add(Point3d::new_raw(0.5, -0.5, 2.0));
+    kd_tree.add(Point3d::new_raw(2.0, 0.0, 0.0));
+
+    // Treat every point as a sphere of radius 0.5
+    let sphere_distance = |content: &Point3d, point: &Point3d| (content - point).length() + 0.5;
+    let query = Point3d::new_raw(1.2, 0.1, 0.0);
+
+    assert_eq!(
+        kd_tree.get_nearest_by(&sphere_distance, &query),
+        Some(Point3d::new_raw(2.0, 0.0, 0.0))
+    );
+    assert_eq!(
+        kd_tree.get_k_nearest_by(&sphere_distance, &query, 3),
+        vec![
+            Point3d::new_raw(2.0, 0.0, 0.0),
+            Point3d::new_raw(0.0, 0.0, 0.0),
+            Point3d::new_raw(1.0, 2.0, 0.0),
+        ]
+    );
+}
+
+#[test]
+fn test_kd_tree_within_radius() {
+    use crate::traits::Norm;
+
+    let mut kd_tree_2d = KdTree::<BoundingBox2d, Point2d>::new();
+    let mut kd_tree_3d = KdTree::<BoundingBox3d, Point3d>::new();
+    let mut points = vec![];
+
+    for i in 0..15 {
+        for j in 0..15 {
+            let point = Point3d::new_raw(0.41 * i as f64 - 3.0, 0.29 * j as f64, 0.1 * i as f64);
+            kd_tree_2d.add(point.as_vec2());
+            kd_tree_3d.add(point);
+            points.push(point);
+        }
+    }
+
+    let query = Point3d::new_raw(-0.5, 1.7, 0.3);
+    let radius = 1.3;
+
+    let expected_2d: HashSet<Point2d> = points
+        .iter()
+        .map(|point| point.as_vec2())
+        .filter(|point| (point - query.as_vec2()).length() <= radius)
+        .collect();
+    let expected_3d: HashSet<Point3d> = points
+        .iter()
+        .filter(|point| (*point - query).length() <= radius)
+        .cloned()
+        .collect();
+
+    assert!(!expected_3d.is_empty());
+    assert!(expected_3d.len() < expected_2d.len());
+    assert_eq!(
+        kd_tree_2d.get_within_radius(&query.as_vec2(), radius),
+        expected_2d
+    );
+    assert_eq!(kd_tree_3d.get_within_radius(&query, radius), expected_3d);
+    assert!(kd_tree_3d
+        .get_within_radius(&Point3d::new_raw(100.0, 0.0, 0.0), radius)
+        .is_empty());
+}
+
+#[test]
+fn test_kd_tree_remove_and_update() {
+    let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::new();
+    let mut points = vec![];
+
+    for i in 0..10 {
+        for j in 0..10 {
+            let point = Point2d::new_raw(0.7 * i as f64 - 2.0, 0.3 * j as f64);
+            kd_tree.add(point);
+            points.push(point);
+        }
+    }
+    assert_eq!(kd_tree.size, 100);
+
+    assert!(!kd_tree.remove(&Point2d::new_raw(0.5, 0.5)));
+    assert_eq!(kd_tree.size, 100);
+
+    for point in &points[3..] {
+        assert!(kd_tree.remove(point));
+    }
+    assert_eq!(kd_tree.size, 3);
+    assert!(kd_tree.root.as_ref().unwrap().children.is_empty());
+
+    let everything =
+        BoundingBox2d::new(Point2d::new_raw(-10.0, -10.0), Point2d::new_raw(10.0, 10.0));
+    assert_eq!(
+        kd_tree.get_intersection(&everything),
+        points[0..3].iter().cloned().collect()
+    );
+
+    assert_eq!(
+        kd_tree.update(&points[0], Point2d::new_raw(5.0, 5.0)),
+        Ok(true)
+    );
+    assert_eq!(kd_tree.size, 3);
+    assert_eq!(
+        kd_tree.nearest(&Point2d::new_raw(4.0, 4.0)),
+        Some(Point2d::new_raw(5.0, 5.0))
+    );
+    assert!(!kd_tree.remove(&points[0]));
+
+    // A missing `old` isn't an insert
+    assert_eq!(
+        kd_tree.update(&points[0], Point2d::new_raw(6.0, 6.0)),
+        Ok(false)
+    );
+    assert_eq!(kd_tree.size, 3);
+
+    // An invalid `new` keeps `old` in place
+    assert_eq!(
+        kd_tree.update(&points[1], Point2d::new_raw(f64::NAN, 0.0)),
+        Err(KdTreeError::NonFiniteBounds)
+    );
+    assert_eq!(kd_tree.size, 3);
+    assert!(kd_tree.find(&points[1]).is_some());
+}
+
+#[test]
+fn test_kd_tree_build() {
+    let spheres = get_test_spheres();
+
+    let mut added = KdTree::<BoundingBox3d, TestSphere>::new();
+    for sphere in &spheres {
+        added.add(sphere.clone());
+    }
+    let sah = KdTree::build(spheres.clone());
+    let median = KdTree::build_with_strategy(spheres.clone(), KdTreeSplitStrategy::Median);
+    assert_eq!(sah.size, spheres.len());
+    assert_eq!(median.size, spheres.len());
+
+    let filter = BoundingBox3d {
+        u: Point3d::new_raw(1.0, 0.5, -1.0),
+        v: Point3d::new_raw(41.0, 1.5, 0.1),
+    };
+    assert_eq!(
+        sah.get_intersection(&filter),
+        added.get_intersection(&filter)
+    );
+    assert_eq!(
+        median.get_intersection(&filter),
+        added.get_intersection(&filter)
+    );
+
+    for i in 0..20 {
+        let cray = ConstrainedRay3d {
+            ray: Ray3d::new(
+                Point3d::new_raw(-5.0, 0.17 * i as f64, 0.3),
+                Point3d::new_raw(1.0, 0.01 * i as f64, 0.02),
+            ),
+            range: (0.0, 100.0),
+        };
+        let expected = added.get_closest_hit(&hit_test_sphere, &cray);
+        assert_eq!(sah.get_closest_hit(&hit_test_sphere, &cray), expected);
+        assert_eq!(median.get_closest_hit(&hit_test_sphere, &cray), expected);
+    }
+
+    let everything =
+        BoundingBox2d::new(Point2d::new_raw(-10.0, -10.0), Point2d::new_raw(10.0, 10.0));
+    let mut empty = KdTree::<BoundingBox2d, Point2d>::build(vec![]);
+    assert!(empty.is_empty());
+    assert!(empty.get_intersection(&everything).is_empty());
+    let handle = empty.add(Point2d::new_raw(0.5, 0.5));
+    assert_eq!(empty.get(handle), Some(&Point2d::new_raw(0.5, 0.5)));
+    assert_eq!(empty.get_intersection(&everything).len(), 1);
+
+    let mut single = KdTree::<BoundingBox2d, Point2d>::build(vec![Point2d::new_raw(3.0, 3.0)]);
+    assert_eq!(single.stats().node_count, 1);
+    assert_eq!(
+        single.get_intersection(&everything),
+        HashSet::from([Point2d::new_raw(3.0, 3.0)])
+    );
+    single.add(Point2d::new_raw(-7.0, 2.0));
+    assert_eq!(single.size, 2);
+    assert_eq!(
+        single.get_intersection(&everything),
+        HashSet::from([Point2d::new_raw(3.0, 3.0), Point2d::new_raw(-7.0, 2.0)])
+    );
+}
+
+#[test]
+fn test_kd_tree_build_coincident() {
+    // Contents no split can separate end up in one leaf instead of recursing
+    let points = vec![Point2d::new_raw(1.0, 2.0); 50];
+    let spheres = vec![
+        TestSphere {
+            center: Point3d::new_raw(1.0, 2.0, 3.0)
+        };
+        50
+    ];
+    for strategy in [
+        KdTreeSplitStrategy::SurfaceAreaHeuristic,
+        KdTreeSplitStrategy::Median,
+    ] {
+        let kd_tree = KdTree::build_with_strategy(points.clone(), strategy);
+        assert_eq!(kd_tree.stats().node_count, 1);
+        let filter = BoundingBox2d::new(Point2d::new_raw(0.0, 0.0), Point2d::new_raw(2.0, 3.0));
+        assert_eq!(kd_tree.get_intersection_handles(&filter).len(), 50);
+
+        let kd_tree = KdTree::<BoundingBox3d, _>::build_with_strategy(spheres.clone(), strategy);
+        assert_eq!(kd_tree.stats().node_count, 1);
+        assert_eq!(kd_tree.len(), 50);
+    }
+}
+
+#[test]
+fn test_kd_tree_build_hit_tests() {
+    use std::cell::Cell;
+
+    // The cost of a closest hit query is dominated by the hit tests on contents
+    let spheres = get_test_spheres();
+    let mut added = KdTree::<BoundingBox3d, TestSphere>::new();
+    for sphere in &spheres {
+        added.add(sphere.clone());
+    }
+    let built = KdTree::build(spheres);
+
+    let calls = Cell::new(0);
+    let counting_hit = |sphere: &TestSphere, cray: &ConstrainedRay3d| {
+        calls.set(calls.get() + 1);
+        hit_test_sphere(sphere, cray)
+    };
+    let count_calls = |kd_tree: &KdTree<BoundingBox3d, TestSphere>| {
+        calls.set(0);
+        for i in 0..100 {
+            let cray = ConstrainedRay3d {
+                ray: Ray3d::new(
+                    Point3d::new_raw(0.037 * i as f64, 0.029 * i as f64, 5.0),
+                    Point3d::new_raw(0.01, 0.02, -1.0),
+                ),
+                range: (0.0, 100.0),
+            };
+            assert_eq!(
+                kd_tree.get_closest_hit(&counting_hit, &cray),
+                added.get_closest_hit(&hit_test_sphere, &cray)
+            );
+        }
+        calls.get()
+    };
+    let added_calls = count_calls(&added);
+    let built_calls = count_calls(&built);
+    assert!(built_calls < added_calls);
+}
+
+#[test]
+fn test_kd_tree_any_hit() {
+    use std::cell::Cell;
+
+    let kd_tree = KdTree::<BoundingBox3d, _>::build(get_test_spheres());
+    let calls = Cell::new(0);
+    let counting_hit = |sphere: &TestSphere, cray: &ConstrainedRay3d| {
+        calls.set(calls.get() + 1);
+        hit_test_sphere(sphere, cray)
+    };
+
+    let blocked = ConstrainedRay3d {
+        ray: Ray3d::new(
+            Point3d::new_raw(-5.0, 0.6, 0.0),
+            Point3d::new_raw(1.0, 0.0, 0.0),
+        ),
+        range: (0.0, 100.0),
+    };
+    kd_tree.get_closest_hit(&counting_hit, &blocked).unwrap();
+    let closest_calls = calls.replace(0);
+
+    let blocker = kd_tree.get_any_hit(&counting_hit, &blocked).unwrap();
+    assert!(hit_test_sphere(&blocker, &blocked).is_some());
+    assert!(calls.get() < closest_calls);
+
+    let short = ConstrainedRay3d {
+        ray: blocked.ray.clone(),
+        range: (0.0, 4.0),
+    };
+    assert_eq!(kd_tree.get_any_hit(&hit_test_sphere, &short), None);
+
+    let free = ConstrainedRay3d {
+        ray: Ray3d::new(
+            Point3d::new_raw(-5.0, 0.6, 5.0),
+            Point3d::new_raw(1.0, 0.0, 0.0),
+        ),
+        range: (0.0, 100.0),
+    };
+    assert_eq!(kd_tree.get_any_hit(&hit_test_sphere, &free), None);
+}
+
+#[test]
+fn test_kd_tree_all_hits() {
+    let spheres = get_test_spheres();
+    let kd_tree = KdTree::<BoundingBox3d, _>::build(spheres.clone());
+
+    let cray = ConstrainedRay3d {
+        ray: Ray3d::new(
+            Point3d::new_raw(-5.0, 0.6, 0.0),
+            Point3d::new_raw(1.0, 0.002, 0.0),
+        ),
+        range: (0.0, 50.0),
+    };
+
+    let mut expected: Vec<(f64, TestSphere)> = spheres
+        .iter()
+        .filter_map(|sphere| hit_test_sphere(sphere, &cray).map(|t| (t, sphere.clone())))
+        .collect();
+    expected.sort_by(|a, b| a.0.total_cmp(&b.0));
+    assert!(expected.len() > 5);
+
+    assert_eq!(kd_tree.get_all_hits(&hit_test_sphere, &cray), expected);
+
+    let mut iterator = kd_tree
+        .get_hit_iterator(&hit_test_sphere, &cray)
+        .map(|(t, handle)| (t, kd_tree.get(handle).unwrap().clone()));
+    assert_eq!(iterator.next(), Some(expected[0].clone()));
+    assert_eq!(iterator.next(), Some(expected[1].clone()));
+}
+
+#[test]
+fn test_kd_tree_ray_2d() {
+    let mut kd_tree = KdTree::<BoundingBox2d, TestSphere>::new();
+    for i in 0..10 {
+        for j in 0..10 {
+            if (i + j) % 3 == 0 {
+                kd_tree.add(TestSphere {
+                    center: Point3d::new_raw(i as f64, j as f64, 0.0),
+                });
+            }
+        }
+    }
+
+    let line_of_sight = |from: Point2d, to: Point2d| {
+        let cray = ConstrainedRay2d {
+            ray: Ray2d::new(from, to - from),
+            range: (0.0, 1.0),
+        };
+        kd_tree.get_any_hit(&hit_test_circle, &cray).is_none()
+    };
+    assert!(line_of_sight(
+        Point2d::new_raw(0.0, 1.5),
+        Point2d::new_raw(9.0, 1.5)
+    ));
+    assert!(!line_of_sight(
+        Point2d::new_raw(-1.0, 0.0),
+        Point2d::new_raw(9.0, 0.0)
+    ));
+
+    let cray = ConstrainedRay2d {
+        ray: Ray2d::new(Point2d::new_raw(-1.0, 3.0), Point2d::new_raw(1.0, 0.0)),
+        range: (0.0, 100.0),
+    };
+    assert_eq!(
+        kd_tree.get_closest_hit(&hit_test_circle, &cray),
+        Some(TestSphere {
+            center: Point3d::new_raw(0.0, 3.0, 0.0)
+        })
+    );
+    assert_eq!(kd_tree.get_all_hits(&hit_test_circle, &cray).len(), 4);
+}
+
+#[test]
+fn test_kd_tree_query_with() {
+    let spheres = get_test_spheres();
+    let mut added = KdTree::<BoundingBox3d, TestSphere>::new();
+    for sphere in &spheres {
+        added.add(sphere.clone());
+    }
+    let built = KdTree::build(spheres);
+
+    for i in 0..10 {
+        let filter = BoundingBox3d {
+            u: Point3d::new_raw(0.37 * i as f64, 0.3 * i as f64, -0.2),
+            v: Point3d::new_raw(0.37 * i as f64 + 2.0, 0.3 * i as f64 + 0.5, 0.7),
+        };
+        let expected = added.get_intersection(&filter);
+
+        for kd_tree in [&added, &built] {
+            let mut visited = vec![];
+            let flow: ControlFlow<()> = kd_tree.query_with(&filter, |sphere| {
+                visited.push(sphere.clone());
+                ControlFlow::Continue(())
+            });
+            assert_eq!(flow, ControlFlow::Continue(()));
+            assert_eq!(visited.len(), expected.len());
+            assert_eq!(visited.into_iter().collect::<HashSet<_>>(), expected);
+        }
+    }
+
+    let filter = BoundingBox3d {
+        u: Point3d::new_raw(-1.0, -1.0, -1.0),
+        v: Point3d::new_raw(100.0, 100.0, 100.0),
+    };
+    let mut count = 0;
+    let flow = built.query_with(&filter, |sphere| {
+        count += 1;
+        if count == 5 {
+            ControlFlow::Break(sphere.clone())
+        } else {
+            ControlFlow::Continue(())
+        }
+    });
+    assert!(flow.is_break());
+    assert_eq!(count, 5);
+}
+
+#[test]
+fn test_kd_tree_query_with_grown_root() {
+    // The upper faces of the old root become shared with its new siblings, where the
+    // query looks for contents touching them
+    for subdivision in [KdTreeSubdivision::Halving, KdTreeSubdivision::Orthants] {
+        let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::with_config(KdTreeConfig {
+            subdivision,
+            initial_root: BoundingBox2d::new(
+                Point2d::new_raw(-10.0, -10.0),
+                Point2d::new_raw(0.0, 0.0),
+            ),
+            ..Default::default()
+        });
+        let boundary = [
+            Point2d::new_raw(0.0, -5.0),
+            Point2d::new_raw(-5.0, 0.0),
+            Point2d::new_raw(0.0, 0.0),
+        ];
+        for point in &boundary {
+            kd_tree.add(*point);
+        }
+        kd_tree.add(Point2d::new_raw(5.0, 5.0));
+        assert!(kd_tree.root.as_ref().unwrap().enclosure.v.t[0] > 0.0);
+
+        for point in &boundary {
+            let filter = BoundingBox2d::new(*point, *point + Point2d::new_raw(1.0, 1.0));
+            let mut visited = vec![];
+            let _: ControlFlow<()> = kd_tree.query_with(&filter, |found| {
+                visited.push(*found);
+                ControlFlow::Continue(())
+            });
+            assert!(visited.contains(point));
+        }
+
+        let filter = BoundingBox2d::new(Point2d::new_raw(-6.0, -6.0), Point2d::new_raw(1.0, 1.0));
+        assert_eq!(kd_tree.get_intersection_handles(&filter).len(), 3);
+    }
+}
+
+#[test]
+fn test_kd_tree_handles() {
+    // Neither Clone nor Eq nor Hash
+    struct Segment {
+        from: Point2d,
+        to: Point2d,
+    }
+
+    impl KdTreeContent<BoundingBox2d> for Segment {
+        fn get_bounding_box(&self) -> BoundingBox2d {
+            let mut result = BoundingBox2d::new(self.from, self.from);
+            for i in 0..=1 {
+                result.u.t[i] = self.from.t[i].min(self.to.t[i]);
+                result.v.t[i] = self.from.t[i].max(self.to.t[i]);
+            }
+            result
+        }
+    }
+
+    let mut kd_tree = KdTree::<BoundingBox2d, Segment>::new();
+    let mut handles = vec![];
+    for i in 0..20 {
+        handles.push(kd_tree.add(Segment {
+            from: Point2d::new_raw(i as f64, 0.0),
+            to: Point2d::new_raw(i as f64 + 3.0, 1.0),
+        }));
+    }
+
+    let filter = BoundingBox2d::new(Point2d::new_raw(4.5, 0.2), Point2d::new_raw(6.5, 0.4));
+    let mut found = kd_tree.get_intersection_handles(&filter);
+    found.sort();
+    assert_eq!(found, handles[2..7].to_vec());
+
+    let removed = kd_tree.remove_handle(handles[4]).unwrap();
+    assert_eq!(removed.from, Point2d::new_raw(4.0, 0.0));
+    assert!(kd_tree.get(handles[4]).is_none());
+    assert!(kd_tree.remove_handle(handles[4]).is_none());
+    assert_eq!(kd_tree.get_intersection_handles(&filter).len(), 4);
+    assert_eq!(kd_tree.size, 19);
+
+    let handle = kd_tree.add(Segment {
+        from: Point2d::new_raw(5.0, 0.3),
+        to: Point2d::new_raw(5.5, 0.3),
+    });
+    assert_eq!(kd_tree.get(handle).unwrap().to, Point2d::new_raw(5.5, 0.3));
+    assert_eq!(kd_tree.get_intersection_handles(&filter).len(), 5);
+
+    // The slot of the removed content is reused, but its old handle stays invalid
+    assert_eq!(handle.index, handles[4].index);
+    assert!(kd_tree.get(handles[4]).is_none());
+    assert!(kd_tree.remove_handle(handles[4]).is_none());
+    assert!(kd_tree.get(handle).is_some());
+
+    kd_tree.clear();
+    let handle = kd_tree.add(Segment {
+        from: Point2d::new_raw(0.0, 0.0),
+        to: Point2d::new_raw(1.0, 1.0),
+    });
+    assert_eq!(handle.index, handles[0].index);
+    assert!(kd_tree.get(handles[0]).is_none());
+    assert!(kd_tree.get(handle).is_some());
+    assert!(kd_tree.freeze().get(handles[0]).is_none());
+}
+
+#[test]
+fn test_kd_tree_config() {
+    fn largest_leaf<BoundingBox: BoundingBoxTrait>(node: &KdNode<BoundingBox>) -> usize {
+        node.children
+            .iter()
+            .map(largest_leaf)
+            .fold(node.content.len(), usize::max)
+    }
+    fn depth<BoundingBox: BoundingBoxTrait>(node: &KdNode<BoundingBox>) -> usize {
+        node.children
+            .iter()
+            .map(|c| depth(c) + 1)
+            .fold(0, usize::max)
+    }
+
+    // Sub-micrometre spacing, below the default minimum cell size
+    let tiny: Vec<Point2d> = (0..100)
+        .map(|i| Point2d::new_raw(1e-10 * (i % 10) as f64, 1e-10 * (i / 10) as f64))
+        .collect();
+    let config = KdTreeConfig {
+        leaf_capacity: 4,
+        min_cell_size: 1e-15,
+        initial_root: BoundingBox2d::new(Point2d::new_raw(0.0, 0.0), Point2d::new_raw(1e-9, 1e-9)),
+        ..Default::default()
+    };
+    let mut kd_tree = KdTree::with_config(config.clone());
+    let mut default_tree = KdTree::new();
+    for point in &tiny {
+        kd_tree.add(*point);
+        default_tree.add(*point);
+    }
+    assert!(largest_leaf(kd_tree.root.as_ref().unwrap()) <= 4);
+    assert!(largest_leaf(default_tree.root.as_ref().unwrap()) > 4);
+    assert_eq!(kd_tree.get_config().leaf_capacity, 4);
+
+    let built = KdTree::build_with_config(tiny.clone(), KdTreeSplitStrategy::Median, config);
+    assert!(largest_leaf(built.root.as_ref().unwrap()) <= 4);
+
+    let query = Point2d::new_raw(3.1e-10, 4.2e-10);
+    assert_eq!(kd_tree.nearest(&query), Some(tiny[43]));
+    assert_eq!(built.nearest(&query), Some(tiny[43]));
+
+    // Planetary distances, beyond the default maximum world size
+    let mut kd_tree = KdTree::<BoundingBox3d, Point3d>::with_config(KdTreeConfig {
+        max_world_size: 1e13,
+        ..Default::default()
+    });
+    let planets: Vec<Point3d> = (0..10)
+        .map(|i| Point3d::new_raw(5.8e10 * i as f64, -1e9 * i as f64, 3e8))
+        .collect();
+    for planet in &planets {
+        kd_tree.add(*planet);
+    }
+    assert_eq!(
+        kd_tree.nearest(&Point3d::new_raw(1.2e11, 0.0, 0.0)),
+        Some(planets[2])
+    );
+
+    let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::with_config(KdTreeConfig {
+        leaf_capacity: 1,
+        max_depth: 3,
+        initial_root: BoundingBox2d::new(Point2d::new_raw(0.0, 0.0), Point2d::new_raw(10.0, 10.0)),
+        ..Default::default()
+    });
+    for point in &tiny {
+        kd_tree.add(*point * 1e10);
+    }
+    assert_eq!(depth(kd_tree.root.as_ref().unwrap()), 3);
+}
+
+#[test]
+fn test_kd_tree_try_add() {
+    let mut kd_tree = KdTree::<BoundingBox3d, BoundingBox3d>::new();
+    let valid = BoundingBox3d {
+        u: Point3d::new_raw(1.0, 2.0, 3.0),
+        v: Point3d::new_raw(4.0, 5.0, 6.0),
+    };
+    let handle = kd_tree.try_add(valid).unwrap();
+    let root = kd_tree.root.as_ref().unwrap().enclosure.clone();
+
+    let nan = Point3d::new_raw(f64::NAN, 0.0, 0.0);
+    let infinite = Point3d::new_raw(0.0, f64::INFINITY, 0.0);
+    let far = Point3d::new_raw(0.0, 0.0, 1e10);
+    let origin = Point3d::new_raw(0.0, 0.0, 0.0);
+    let cases = [
+        (
+            BoundingBox3d { u: nan, v: origin },
+            KdTreeError::NonFiniteBounds,
+        ),
+        (
+            BoundingBox3d {
+                u: origin,
+                v: infinite,
+            },
+            KdTreeError::NonFiniteBounds,
+        ),
+        (
+            BoundingBox3d { u: far, v: origin },
+            KdTreeError::DegenerateBounds,
+        ),
+        (
+            BoundingBox3d { u: origin, v: far },
+            KdTreeError::ExceedsWorldSize,
+        ),
+    ];
+    for (enclosure, error) in cases {
+        assert_eq!(kd_tree.try_add(enclosure).err(), Some(error));
+    }
+
+    for i in 0..3 {
+        assert_eq!(
+            kd_tree.root.as_ref().unwrap().enclosure.get_bounds(i),
+            root.get_bounds(i)
+        );
+    }
+    assert_eq!(kd_tree.contents.len(), 1);
+    assert_eq!(kd_tree.get_intersection_handles(&root), vec![handle]);
+
+    let mut kd_tree = KdTree::<BoundingBox3d, Point3d>::with_config(KdTreeConfig {
+        max_world_size: 1e11,
+        ..Default::default()
+    });
+    assert!(kd_tree.try_add(far).is_ok());
+}
+
+#[test]
+fn test_kd_tree_collection() {
+    let points: Vec<Point2d> = (0..50)
+        .map(|i| Point2d::new_raw(0.7 * i as f64, (0.3 * i as f64).sin()))
+        .collect();
+    let sorted = |mut points: Vec<Point2d>| {
+        points.sort_by(|a, b| a.t[0].total_cmp(&b.t[0]));
+        points
+    };
+
+    let mut kd_tree: KdTree<BoundingBox2d, Point2d> = points[..30].iter().cloned().collect();
+    assert_eq!(kd_tree.len(), 30);
+    kd_tree.extend(points[30..].iter().cloned());
+    assert_eq!(kd_tree.len(), 50);
+    assert_eq!(sorted(kd_tree.iter().cloned().collect()), points);
+
+    assert!(kd_tree.remove(&points[10]));
+    assert_eq!(kd_tree.len(), 49);
+    assert_eq!((&kd_tree).into_iter().count(), 49);
+    assert!(!kd_tree.iter().any(|point| *point == points[10]));
+
+    let drained = sorted(kd_tree.into_iter().collect());
+    assert_eq!(drained.len(), 49);
+    assert_eq!(drained[10], points[11]);
+
+    let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::new();
+    assert!(kd_tree.is_empty());
+    kd_tree.extend(points.iter().cloned());
+    kd_tree.clear();
+    assert!(kd_tree.is_empty());
+    assert_eq!(kd_tree.iter().count(), 0);
+    assert_eq!(kd_tree.nearest(&points[3]), None);
+    kd_tree.add(points[3]);
+    assert_eq!(kd_tree.nearest(&points[0]), Some(points[3]));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_kd_tree_serde() {
+    use crate::frozen_kd_tree::FrozenKdTree;
+
+    let points: Vec<Point3d> = (0..300)
+        .map(|i| {
+            let i = i as f64;
+            Point3d::new_raw((0.37 * i).sin() * 5.0, (0.11 * i).cos() * 3.0, 0.01 * i)
+        })
+        .collect();
+    let mut kd_tree = KdTree::<BoundingBox3d, Point3d>::new();
+    let handles: Vec<KdTreeHandle> = points.iter().map(|point| kd_tree.add(*point)).collect();
+    kd_tree.remove_handle(handles[17]);
+
+    let json = serde_json::to_string(&kd_tree).unwrap();
+    let mut copy: KdTree<BoundingBox3d, Point3d> = serde_json::from_str(&json).unwrap();
+    assert_eq!(copy.len(), kd_tree.len());
+    assert_eq!(copy.stats(), kd_tree.stats());
+    assert_eq!(copy.get(handles[42]), Some(&points[42]));
+    assert_eq!(copy.get(handles[17]), None);
+
+    for i in 0..20 {
+        let query = Point3d::new_raw(0.5 * i as f64 - 5.0, 0.2 * i as f64 - 2.0, 0.1 * i as f64);
+        assert_eq!(copy.k_nearest(&query, 5), kd_tree.k_nearest(&query, 5));
+        let filter = BoundingBox3d {
+            u: query,
+            v: query + Point3d::new_raw(2.0, 2.0, 1.0),
+        };
+        let mut expected = kd_tree.get_intersection_handles(&filter);
+        let mut actual = copy.get_intersection_handles(&filter);
+        expected.sort();
+        actual.sort();
+        assert_eq!(actual, expected);
+    }
+
+    // The free list survives, so the deserialized tree reuses the same slots
+    assert_eq!(copy.add(points[17]), kd_tree.add(points[17]));
+
+    let kd_tree_value = serde_json::to_value(&kd_tree).unwrap();
+    let frozen = kd_tree.freeze();
+    let json = serde_json::to_string(&frozen).unwrap();
+    let copy: FrozenKdTree<BoundingBox3d, Point3d> = serde_json::from_str(&json).unwrap();
+    let filter = BoundingBox3d {
+        u: Point3d::new_raw(-1.0, -1.0, 0.0),
+        v: Point3d::new_raw(1.0, 1.0, 3.0),
+    };
+    assert_eq!(
+        copy.get_intersection(&filter),
+        frozen.get_intersection(&filter)
+    );
+    assert_eq!(copy.get(handles[42]), Some(&points[42]));
+
+    // Payloads breaking the invariants of the trees are rejected
+    let changes: [&dyn Fn(&mut serde_json::Value); 6] = [
+        &|value| value["free"] = serde_json::json!([0]),
+        &|value| value["size"] = serde_json::json!(1),
+        &|value| value["root"] = serde_json::Value::Null,
+        &|value| value["generations"] = serde_json::json!([]),
+        &|value| value["root"]["content"] = serde_json::json!([1000]),
+        &|value| value["root"]["children"] = serde_json::json!([]),
+    ];
+    for change in changes {
+        let mut value = kd_tree_value.clone();
+        change(&mut value);
+        assert!(serde_json::from_value::<KdTree<BoundingBox3d, Point3d>>(value).is_err());
+    }
+
+    let frozen_value = serde_json::to_value(&frozen).unwrap();
+    let changes: [&dyn Fn(&mut serde_json::Value); 4] = [
+        &|value| value["content_indices"][0] = serde_json::json!(1000),
+        &|value| value["nodes"][0]["first_child"] = serde_json::json!(0),
+        &|value| value["nodes"][0]["child_count"] = serde_json::json!(1000),
+        &|value| value["nodes"][1]["content_count"] = serde_json::json!(1000),
+    ];
+    for change in changes {
+        let mut value = frozen_value.clone();
+        change(&mut value);
+        assert!(serde_json::from_value::<FrozenKdTree<BoundingBox3d, Point3d>>(value).is_err());
+    }
+}
+
+#[test]
+fn test_kd_tree_region() {
+    // Everything below an inside node is taken without testing the contents
+    struct Everywhere;
+
+    impl KdTreeRegion<BoundingBox2d, Point2d> for Everywhere {
+        fn node_overlap(&self, _: &BoundingBox2d) -> Overlap {
+            Overlap::Inside
+        }
+
+        fn contains(&self, _: &Point2d) -> bool {
+            unreachable!()
+        }
+    }
+
+    let points: Vec<Point2d> = (0..2000)
+        .map(|i| {
+            let i = i as f64;
+            Point2d::new_raw((0.71 * i).sin() * 10.0, (0.37 * i).cos() * 6.0)
+        })
+        .collect();
+    let built = KdTree::build(points.clone());
+    let mut added = KdTree::<BoundingBox2d, Point2d>::new();
+    added.extend(points.iter().cloned());
+
+    let disc = Disc2d::new(Point2d::new_raw(1.5, -0.5), 3.0);
+    let expected: HashSet<Point2d> = points
+        .iter()
+        .filter(|p| disc.contains_point(p))
+        .cloned()
+        .collect();
+    let filter = BoundingBox2d::new(Point2d::new_raw(-2.0, -1.0), Point2d::new_raw(4.0, 3.0));
+    assert!(expected.len() > 100);
+
+    for kd_tree in [built, added] {
+        assert_eq!(kd_tree.get_in_region(&Everywhere).len(), points.len());
+        assert_eq!(kd_tree.get_in_region(&disc), expected);
+        assert_eq!(kd_tree.get_in_region_handles(&disc).len(), expected.len());
+        assert_eq!(
+            kd_tree.get_in_region(&filter),
+            kd_tree.get_intersection(&filter)
+        );
     }
 }
@@ -0,0 +1,148 @@
+use crate::kd_tree::*;
+use crate::kd_tree_traits::*;
+#[cfg(test)]
+use crate::ray_box::*;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KdTreeStats {
+    pub depth: usize, // A tree consisting only of its root has depth 0
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub leaf_histogram: Vec<usize>, // Number of leaves holding as many contents as the index
+    pub duplication_factor: f64,    // Average number of leaves a content is stored in
+    pub empty_leaf_ratio: f64,
+}
+
+// Yields the enclosure of every node together with its depth, parents before their children
+pub struct KdTreeEnclosureIterator<'a, BoundingBox: BoundingBoxTrait> {
+    stack: Vec<(usize, &'a KdNode<BoundingBox>)>,
+}
+
+impl<'a, BoundingBox: BoundingBoxTrait> Iterator for KdTreeEnclosureIterator<'a, BoundingBox> {
+    type Item = (usize, &'a BoundingBox);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (depth, node) = self.stack.pop()?;
+        self.stack
+            .extend(node.children.iter().rev().map(|child| (depth + 1, child)));
+        Some((depth, &node.enclosure))
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    KdTree<BoundingBox, Content>
+{
+    pub fn enclosures(&self) -> KdTreeEnclosureIterator<'_, BoundingBox> {
+        KdTreeEnclosureIterator {
+            stack: self.root.iter().map(|root| (0, root)).collect(),
+        }
+    }
+
+    pub fn stats(&self) -> KdTreeStats {
+        let mut stats = KdTreeStats {
+            depth: 0,
+            node_count: 0,
+            leaf_count: 0,
+            leaf_histogram: vec![],
+            duplication_factor: 0.0,
+            empty_leaf_ratio: 0.0,
+        };
+
+        let mut references = 0;
+        let mut stack: Vec<(usize, &KdNode<BoundingBox>)> =
+            self.root.iter().map(|root| (0, root)).collect();
+        while let Some((depth, node)) = stack.pop() {
+            stats.depth = stats.depth.max(depth);
+            stats.node_count += 1;
+            references += node.content.len();
+
+            if node.children.is_empty() {
+                stats.leaf_count += 1;
+                if stats.leaf_histogram.len() <= node.content.len() {
+                    stats.leaf_histogram.resize(node.content.len() + 1, 0);
+                }
+                stats.leaf_histogram[node.content.len()] += 1;
+            }
+            stack.extend(node.children.iter().map(|child| (depth + 1, child)));
+        }
+
+        if !self.is_empty() {
+            stats.duplication_factor = references as f64 / self.len() as f64;
+        }
+        if stats.leaf_count > 0 {
+            let empty = stats.leaf_histogram.first().copied().unwrap_or(0);
+            stats.empty_leaf_ratio = empty as f64 / stats.leaf_count as f64;
+        }
+        stats
+    }
+}
+
+#[test]
+fn test_kd_tree_stats() {
+    let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::new();
+    let stats = kd_tree.stats();
+    assert_eq!(stats.depth, 0);
+    assert_eq!(stats.node_count, 1);
+    assert_eq!(stats.leaf_histogram, vec![1]);
+    assert_eq!(stats.duplication_factor, 0.0);
+    assert_eq!(stats.empty_leaf_ratio, 1.0);
+
+    for i in 0..10 {
+        for j in 0..10 {
+            kd_tree.add(Point2d::new_raw(
+                0.3 * i as f64 + 0.01,
+                0.2 * j as f64 + 0.01,
+            ));
+        }
+    }
+    kd_tree.add(BoundingBox2d::default().v);
+
+    let stats = kd_tree.stats();
+    let enclosures: Vec<_> = kd_tree.enclosures().collect();
+    assert_eq!(enclosures.len(), stats.node_count);
+    assert_eq!(enclosures.iter().map(|(d, _)| *d).max(), Some(stats.depth));
+    assert_eq!(stats.leaf_histogram.iter().sum::<usize>(), stats.leaf_count);
+    assert_eq!(stats.node_count, 2 * stats.leaf_count - 1);
+    assert!(stats.leaf_histogram.len() <= kd_tree.get_config().leaf_capacity + 1);
+    assert!(stats.duplication_factor > 1.0);
+    assert!(0.0 < stats.empty_leaf_ratio && stats.empty_leaf_ratio < 1.0);
+
+    // Depth first: every node directly follows its parent or a node of the same or lower depth
+    assert_eq!(enclosures[0].0, 0);
+    for pair in enclosures.windows(2) {
+        assert!(pair[1].0 <= pair[0].0 + 1);
+        if pair[1].0 == pair[0].0 + 1 {
+            assert!(pair[1].1.is_contained(pair[0].1));
+        }
+    }
+}
+
+#[test]
+fn test_kd_tree_stats_config() {
+    // Leaves stay within leaf_capacity until max_depth stops the splitting
+    let points: Vec<Point2d> = (0..100)
+        .map(|i| Point2d::new_raw((i % 10) as f64, (i / 10) as f64))
+        .collect();
+    for (max_depth, expected_depth) in [(64, None), (3, Some(3))] {
+        let mut kd_tree = KdTree::<BoundingBox2d, Point2d>::with_config(KdTreeConfig {
+            leaf_capacity: 2,
+            max_depth,
+            initial_root: BoundingBox2d::new(
+                Point2d::new_raw(0.0, 0.0),
+                Point2d::new_raw(10.0, 10.0),
+            ),
+            ..Default::default()
+        });
+        for point in &points {
+            kd_tree.add(*point);
+        }
+        let stats = kd_tree.stats();
+        match expected_depth {
+            Some(depth) => {
+                assert_eq!(stats.depth, depth);
+                assert!(stats.leaf_histogram.len() > 3);
+            }
+            None => assert!(stats.leaf_histogram.len() <= 3),
+        }
+    }
+}
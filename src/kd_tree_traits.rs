@@ -2,17 +2,42 @@ use crate::ray_box::*;
 use crate::vec3::*;
 
 pub trait BoundingBoxTrait: Sized + Clone + Default + std::fmt::Debug {
-    fn partition(&self) -> Option<(Self, Self)>;
-    fn extend(&self) -> Option<(Self, Self)>; // First is other, Second new parent
+    const DIMENSIONS: usize;
+
+    fn partition(&self, min_size: f64) -> Option<(Self, Self)>;
+    fn extend(&self, max_size: f64) -> Option<(Self, Self)>; // First is other, Second new parent
 
     fn is_contained(&self, other: &Self) -> bool;
     fn intersects(&self, other: &Self) -> bool;
 
     fn is_sub_scale(&self, other: &Self) -> bool;
+
+    fn get_bounds(&self, dimension: usize) -> (f64, f64);
+    fn set_bounds(&mut self, dimension: usize, bounds: (f64, f64));
+    fn union(&self, other: &Self) -> Self;
+    fn get_surface_area(&self) -> f64; // Perimeter for 2d boxes
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overlap {
+    Outside,
+    Partial,
+    Inside,
+}
+
+pub trait ConstrainedRayTrait {
+    fn get_range(&self) -> (f64, f64);
 }
 
 pub trait HittableBoundingBoxTrait: BoundingBoxTrait {
-    fn hit(&self, ray: &ConstrainedRay3d) -> HitBoxResult;
+    type Ray: ConstrainedRayTrait;
+    fn hit(&self, ray: &Self::Ray) -> HitBoxResult;
+}
+
+pub trait DistanceBoundingBoxTrait: BoundingBoxTrait {
+    type Point;
+    fn distance(&self, point: &Self::Point) -> f64;
+    fn farthest_distance(&self, point: &Self::Point) -> f64; // To the farthest corner of the box
 }
 
 impl BoundingBox2d {
@@ -38,8 +63,10 @@ impl BoundingBox2d {
 }
 
 impl BoundingBoxTrait for BoundingBox2d {
-    fn partition(&self) -> Option<(Self, Self)> {
-        if (self.u - self.v).length() < 1e-8 {
+    const DIMENSIONS: usize = 2;
+
+    fn partition(&self, min_size: f64) -> Option<(Self, Self)> {
+        if (self.u - self.v).length() < min_size {
             return None;
         }
 
@@ -63,8 +90,8 @@ impl BoundingBoxTrait for BoundingBox2d {
         ))
     }
 
-    fn extend(&self) -> Option<(Self, Self)> {
-        if (self.u - self.v).length() > 1e8 {
+    fn extend(&self, max_size: f64) -> Option<(Self, Self)> {
+        if (self.u - self.v).length() > max_size {
             return None;
         }
 
@@ -88,19 +115,20 @@ impl BoundingBoxTrait for BoundingBox2d {
         let mut template = self.clone();
 
         if -left_clamped < right_clamped {
+            // The shared face is copied rather than computed so the boxes meet exactly
             template.u.t[dim] -= dif;
-            template.v.t[dim] -= dif;
+            template.v.t[dim] = self.u.t[dim];
             let parent = Self {
-                u: template.u.clone(),
-                v: self.v.clone(),
+                u: template.u,
+                v: self.v,
             };
             Some((template, parent))
         } else {
-            template.u.t[dim] += dif;
+            template.u.t[dim] = self.v.t[dim];
             template.v.t[dim] += dif;
             let parent = Self {
-                u: self.u.clone(),
-                v: template.v.clone(),
+                u: self.u,
+                v: template.v,
             };
             Some((template, parent))
         }
@@ -120,6 +148,29 @@ impl BoundingBoxTrait for BoundingBox2d {
     fn is_sub_scale(&self, other: &Self) -> bool {
         (self.v - self.u).length() <= (other.v - other.u).length()
     }
+
+    fn get_bounds(&self, dimension: usize) -> (f64, f64) {
+        (self.u.t[dimension], self.v.t[dimension])
+    }
+
+    fn set_bounds(&mut self, dimension: usize, bounds: (f64, f64)) {
+        self.u.t[dimension] = bounds.0;
+        self.v.t[dimension] = bounds.1;
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        let mut result = self.clone();
+        for i in 0..Self::DIMENSIONS {
+            result.u.t[i] = self.u.t[i].min(other.u.t[i]);
+            result.v.t[i] = self.v.t[i].max(other.v.t[i]);
+        }
+        result
+    }
+
+    fn get_surface_area(&self) -> f64 {
+        let dif = self.v - self.u;
+        2.0 * (dif.t[0] + dif.t[1])
+    }
 }
 
 impl BoundingBox3d {
@@ -149,8 +200,10 @@ impl BoundingBox3d {
 }
 
 impl BoundingBoxTrait for BoundingBox3d {
-    fn partition(&self) -> Option<(Self, Self)> {
-        if (self.u - self.v).length() < 1e-8 {
+    const DIMENSIONS: usize = 3;
+
+    fn partition(&self, min_size: f64) -> Option<(Self, Self)> {
+        if (self.u - self.v).length() < min_size {
             return None;
         }
 
@@ -174,8 +227,8 @@ impl BoundingBoxTrait for BoundingBox3d {
         ))
     }
 
-    fn extend(&self) -> Option<(Self, Self)> {
-        if (self.u - self.v).length() > 1e8 {
+    fn extend(&self, max_size: f64) -> Option<(Self, Self)> {
+        if (self.u - self.v).length() > max_size {
             return None;
         }
 
@@ -199,18 +252,18 @@ impl BoundingBoxTrait for BoundingBox3d {
 
         if -left_clamped < right_clamped {
             template.u.t[dim] -= dif;
-            template.v.t[dim] -= dif;
+            template.v.t[dim] = self.u.t[dim];
             let parent = Self {
-                u: template.u.clone(),
-                v: self.v.clone(),
+                u: template.u,
+                v: self.v,
             };
             Some((template, parent))
         } else {
-            template.u.t[dim] += dif;
+            template.u.t[dim] = self.v.t[dim];
             template.v.t[dim] += dif;
             let parent = Self {
-                u: self.u.clone(),
-                v: template.v.clone(),
+                u: self.u,
+                v: template.v,
             };
             Some((template, parent))
         }
@@ -232,18 +285,114 @@ impl BoundingBoxTrait for BoundingBox3d {
     fn is_sub_scale(&self, other: &Self) -> bool {
         (self.v - self.u).length() <= (other.v - other.u).length()
     }
+
+    fn get_bounds(&self, dimension: usize) -> (f64, f64) {
+        (self.u.t[dimension], self.v.t[dimension])
+    }
+
+    fn set_bounds(&mut self, dimension: usize, bounds: (f64, f64)) {
+        self.u.t[dimension] = bounds.0;
+        self.v.t[dimension] = bounds.1;
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        let mut result = self.clone();
+        for i in 0..Self::DIMENSIONS {
+            result.u.t[i] = self.u.t[i].min(other.u.t[i]);
+            result.v.t[i] = self.v.t[i].max(other.v.t[i]);
+        }
+        result
+    }
+
+    fn get_surface_area(&self) -> f64 {
+        let dif = self.v - self.u;
+        2.0 * (dif.t[0] * dif.t[1] + dif.t[0] * dif.t[2] + dif.t[1] * dif.t[2])
+    }
+}
+
+impl ConstrainedRayTrait for ConstrainedRay2d {
+    fn get_range(&self) -> (f64, f64) {
+        self.range
+    }
+}
+
+impl ConstrainedRayTrait for ConstrainedRay3d {
+    fn get_range(&self) -> (f64, f64) {
+        self.range
+    }
+}
+
+impl HittableBoundingBoxTrait for BoundingBox2d {
+    type Ray = ConstrainedRay2d;
+
+    fn hit(&self, ray: &ConstrainedRay2d) -> HitBoxResult {
+        self.is_hit_by_ray(ray)
+    }
 }
 
 impl HittableBoundingBoxTrait for BoundingBox3d {
+    type Ray = ConstrainedRay3d;
+
     fn hit(&self, ray: &ConstrainedRay3d) -> HitBoxResult {
-        self.is_hit_by_ray(&ray)
+        self.is_hit_by_ray(ray)
+    }
+}
+
+impl DistanceBoundingBoxTrait for BoundingBox2d {
+    type Point = Point2d;
+
+    fn distance(&self, point: &Point2d) -> f64 {
+        let mut closest = *point;
+        for i in 0..=1 {
+            closest.t[i] = closest.t[i].max(self.u.t[i]).min(self.v.t[i]);
+        }
+        (closest - point).length()
+    }
+
+    fn farthest_distance(&self, point: &Point2d) -> f64 {
+        let mut farthest = self.u;
+        for i in 0..=1 {
+            if point.t[i] - self.u.t[i] < self.v.t[i] - point.t[i] {
+                farthest.t[i] = self.v.t[i];
+            }
+        }
+        (farthest - point).length()
+    }
+}
+
+impl DistanceBoundingBoxTrait for BoundingBox3d {
+    type Point = Point3d;
+
+    fn distance(&self, point: &Point3d) -> f64 {
+        let mut closest = *point;
+        for i in 0..=2 {
+            closest.t[i] = closest.t[i].max(self.u.t[i]).min(self.v.t[i]);
+        }
+        (closest - point).length()
+    }
+
+    fn farthest_distance(&self, point: &Point3d) -> f64 {
+        let mut farthest = self.u;
+        for i in 0..=2 {
+            if point.t[i] - self.u.t[i] < self.v.t[i] - point.t[i] {
+                farthest.t[i] = self.v.t[i];
+            }
+        }
+        (farthest - point).length()
     }
 }
 
-pub trait KdTreeContent<BoundingBox: BoundingBoxTrait>: Clone + Eq + std::hash::Hash {
+pub trait KdTreeContent<BoundingBox: BoundingBoxTrait> {
     fn get_bounding_box(&self) -> BoundingBox;
 }
 
+// A content counts as inside when it overlaps the region at all. Everything stored below a node
+// reported as Inside is taken without calling contains.
+pub trait KdTreeRegion<BoundingBox: BoundingBoxTrait, Content> {
+    fn node_overlap(&self, enclosure: &BoundingBox) -> Overlap;
+    fn contains(&self, content: &Content) -> bool;
+}
+
 impl KdTreeContent<BoundingBox2d> for Point2d {
     fn get_bounding_box(&self) -> BoundingBox2d {
         BoundingBox2d { u: *self, v: *self }
@@ -255,3 +404,35 @@ impl KdTreeContent<BoundingBox3d> for Point3d {
         BoundingBox3d { u: *self, v: *self }
     }
 }
+
+impl<Content: KdTreeContent<BoundingBox2d>> KdTreeRegion<BoundingBox2d, Content> for BoundingBox2d {
+    fn node_overlap(&self, enclosure: &BoundingBox2d) -> Overlap {
+        if !self.intersects(enclosure) {
+            Overlap::Outside
+        } else if enclosure.is_contained(self) {
+            Overlap::Inside
+        } else {
+            Overlap::Partial
+        }
+    }
+
+    fn contains(&self, content: &Content) -> bool {
+        self.intersects(&content.get_bounding_box())
+    }
+}
+
+impl<Content: KdTreeContent<BoundingBox3d>> KdTreeRegion<BoundingBox3d, Content> for BoundingBox3d {
+    fn node_overlap(&self, enclosure: &BoundingBox3d) -> Overlap {
+        if !self.intersects(enclosure) {
+            Overlap::Outside
+        } else if enclosure.is_contained(self) {
+            Overlap::Inside
+        } else {
+            Overlap::Partial
+        }
+    }
+
+    fn contains(&self, content: &Content) -> bool {
+        self.intersects(&content.get_bounding_box())
+    }
+}
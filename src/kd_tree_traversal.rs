@@ -0,0 +1,167 @@
+use crate::kd_tree::*;
+use crate::kd_tree_traits::*;
+use crate::ray_box::*;
+use std::ops::ControlFlow;
+
+// Read access to the nodes of KdTree and FrozenKdTree, so both share their traversals
+pub(crate) trait KdTreeNodes<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> {
+    type Node<'a>: Copy
+    where
+        Self: 'a;
+
+    fn get_root(&self) -> Option<Self::Node<'_>>;
+    fn get_enclosure<'a>(&'a self, node: Self::Node<'a>) -> &'a BoundingBox;
+    fn get_node_content<'a>(&'a self, node: Self::Node<'a>) -> &'a [u32];
+    fn get_child_count<'a>(&'a self, node: Self::Node<'a>) -> usize;
+    fn get_child<'a>(&'a self, node: Self::Node<'a>, i: usize) -> Self::Node<'a>;
+    fn get_content(&self, index: u32) -> &Content;
+
+    fn query<B, F>(&self, filter: &BoundingBox, mut fun: F) -> ControlFlow<B>
+    where
+        F: FnMut(u32, &Content) -> ControlFlow<B>,
+    {
+        let root = match self.get_root() {
+            Some(root) => root,
+            None => return ControlFlow::Continue(()),
+        };
+        let root_enclosure = self.get_enclosure(root);
+
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            let enclosure = self.get_enclosure(node);
+            if !enclosure.intersects(filter) {
+                continue;
+            }
+
+            for &index in self.get_node_content(node) {
+                let content = self.get_content(index);
+                let content_enclosure = content.get_bounding_box();
+                if content_enclosure.intersects(filter)
+                    && KdTree::<BoundingBox, Content>::is_reference_node(
+                        enclosure,
+                        root_enclosure,
+                        &content_enclosure,
+                        filter,
+                    )
+                {
+                    fun(index, content)?;
+                }
+            }
+
+            stack.extend((0..self.get_child_count(node)).map(|i| self.get_child(node, i)));
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    // Indices of the contents in the region, sorted and without duplicates
+    fn get_in_region_indices<R>(&self, region: &R) -> Vec<u32>
+    where
+        R: KdTreeRegion<BoundingBox, Content>,
+    {
+        let mut result = vec![];
+        let mut stack: Vec<(bool, Self::Node<'_>)> = self
+            .get_root()
+            .map(|root| (false, root))
+            .into_iter()
+            .collect();
+        while let Some((is_inside, node)) = stack.pop() {
+            let is_inside = is_inside
+                || match region.node_overlap(self.get_enclosure(node)) {
+                    Overlap::Outside => continue,
+                    Overlap::Partial => false,
+                    Overlap::Inside => true,
+                };
+
+            // Every content stored below an inside node touches it, so no further tests are needed
+            if is_inside {
+                result.extend_from_slice(self.get_node_content(node));
+            } else {
+                result.extend(
+                    self.get_node_content(node)
+                        .iter()
+                        .filter(|&&index| region.contains(self.get_content(index))),
+                );
+            }
+            stack.extend(
+                (0..self.get_child_count(node)).map(|i| (is_inside, self.get_child(node, i))),
+            );
+        }
+
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    fn push_hit_children<'a>(
+        &'a self,
+        node: Self::Node<'a>,
+        cray: &BoundingBox::Ray,
+        stack: &mut Vec<(f64, Self::Node<'a>)>,
+    ) where
+        BoundingBox: HittableBoundingBoxTrait + 'a,
+    {
+        let start = stack.len();
+        for i in 0..self.get_child_count(node) {
+            let child = self.get_child(node, i);
+            match self.get_enclosure(child).hit(cray) {
+                HitBoxResult::Miss => (),
+                HitBoxResult::Inside(_) => stack.push((cray.get_range().0, child)),
+                HitBoxResult::Outside(close, _) => stack.push((close, child)),
+            }
+        }
+        // The closest child has to be on top of the stack
+        stack[start..].sort_by(|a, b| b.0.total_cmp(&a.0));
+    }
+
+    fn get_closest_hit_index<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Option<u32>
+    where
+        BoundingBox: HittableBoundingBoxTrait,
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+    {
+        let mut result = None;
+        let mut current = cray.get_range().1;
+        let mut stack = vec![(cray.get_range().0, self.get_root()?)];
+
+        while let Some((close, node)) = stack.pop() {
+            // Farther children are skipped once a closer hit is known
+            if close > current {
+                continue;
+            }
+
+            for &index in self.get_node_content(node) {
+                if let Some(candidate) = fun(self.get_content(index), cray) {
+                    if candidate < current {
+                        current = candidate;
+                        result = Some(index);
+                    }
+                }
+            }
+            self.push_hit_children(node, cray, &mut stack);
+        }
+
+        result
+    }
+
+    fn get_any_hit_index<F>(&self, fun: &F, cray: &BoundingBox::Ray) -> Option<u32>
+    where
+        BoundingBox: HittableBoundingBoxTrait,
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64>,
+    {
+        let (lower, upper) = cray.get_range();
+        let mut stack = vec![(lower, self.get_root()?)];
+
+        while let Some((_, node)) = stack.pop() {
+            for &index in self.get_node_content(node) {
+                if let Some(candidate) = fun(self.get_content(index), cray) {
+                    if lower <= candidate && candidate <= upper {
+                        return Some(index);
+                    }
+                }
+            }
+            self.push_hit_children(node, cray, &mut stack);
+        }
+
+        None
+    }
+}
@@ -1,16 +1,43 @@
+mod aabb_tree;
+mod frozen_kd_tree;
+mod frustum;
 mod kd_tree;
+mod kd_tree_stats;
 mod kd_tree_traits;
+mod kd_tree_traversal;
 mod mat2;
 mod mat3;
+mod mat4;
+mod orthtree;
+#[cfg(feature = "rayon")]
+mod par_kd_tree;
+mod quat;
 mod ray_box;
+mod spatial_hash;
+mod sphere;
+#[cfg(test)]
+mod test_shapes;
 mod traits;
+mod transform;
 mod vec2;
 mod vec3;
+mod vec4;
 
+pub use crate::aabb_tree::*;
+pub use crate::frozen_kd_tree::*;
+pub use crate::frustum::*;
 pub use crate::kd_tree::*;
+pub use crate::kd_tree_stats::*;
 pub use crate::kd_tree_traits::*;
 pub use crate::mat2::*;
 pub use crate::mat3::*;
+pub use crate::mat4::*;
+pub use crate::orthtree::*;
+pub use crate::quat::*;
 pub use crate::ray_box::*;
+pub use crate::spatial_hash::*;
+pub use crate::sphere::*;
+pub use crate::transform::*;
 pub use crate::vec2::*;
 pub use crate::vec3::*;
+pub use crate::vec4::*;
@@ -1,6 +1,8 @@
-pub use crate::vec2::*;
+use crate::traits::*;
+use crate::vec2::*;
 use std::ops::*;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Copy, Clone, Debug)]
 pub struct Mat2<T: Copy> {
     pub r: Vec2<Vec2<T>>,
@@ -12,6 +14,41 @@ impl<T: Copy> Mat2<T> {
             r: Vec2::<Vec2<T>>::new(row1, row2),
         }
     }
+
+    pub fn from_cols(col1: Vec2<T>, col2: Vec2<T>) -> Self {
+        Self::new(
+            Vec2::new(col1.t[0], col2.t[0]),
+            Vec2::new(col1.t[1], col2.t[1]),
+        )
+    }
+
+    pub fn transpose(&self) -> Self {
+        Self::from_cols(self.r.t[0], self.r.t[1])
+    }
+}
+
+impl<T: From<u8> + Copy> Mat2<T> {
+    pub fn zero() -> Self {
+        let zero = T::from(0);
+        Self::new(Vec2::new(zero, zero), Vec2::new(zero, zero))
+    }
+
+    pub fn identity() -> Self {
+        let (zero, one) = (T::from(0), T::from(1));
+        Self::new(Vec2::new(one, zero), Vec2::new(zero, one))
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Mat2<T> {
+    pub fn trace(&self) -> T {
+        self.r.t[0].t[0] + self.r.t[1].t[1]
+    }
+}
+
+impl<T: Mul<T, Output = T> + Sub<T, Output = T> + Copy> Mat2<T> {
+    pub fn determinant(&self) -> T {
+        self.r.t[0].t[0] * self.r.t[1].t[1] - self.r.t[0].t[1] * self.r.t[1].t[0]
+    }
 }
 
 impl Mat2<f64> {
@@ -25,6 +62,22 @@ impl Mat2<f64> {
     pub fn new_rot90() -> Self {
         Self::new_rot(std::f64::consts::PI / 2.0)
     }
+
+    pub fn inverse(&self) -> Option<Self> {
+        let scale = self
+            .r
+            .t
+            .iter()
+            .flat_map(|row| row.t)
+            .fold(0.0, |a: f64, b| a.max(b.abs()));
+        let determinant = self.determinant();
+        if determinant.abs() <= SINGULAR_TOLERANCE * scale * scale {
+            return None;
+        }
+
+        let [[a, b], [c, d]] = self.r.t.map(|row| row.t);
+        Some(Self::new(Vec2d::new_raw(d, -b), Vec2d::new_raw(-c, a)) * (1.0 / determinant))
+    }
 }
 
 impl<T: Neg<Output = T> + Copy> Neg for &Mat2<T> {
@@ -151,6 +204,7 @@ impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Mat2<T>> for Mat2<T>
 impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Mat2<T>> for &Mat2<T> {
     type Output = Mat2<T>;
 
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
     fn mul(self, rhs: Mat2<T>) -> Self::Output {
         self * &rhs
     }
@@ -159,6 +213,7 @@ impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Mat2<T>> for &Mat2<T
 impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Mat2<T>> for Mat2<T> {
     type Output = Mat2<T>;
 
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
     fn mul(mut self, rhs: &Mat2<T>) -> Self::Output {
         self = &self * rhs;
         self
@@ -176,7 +231,7 @@ impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Vec2<T>> for &Mat2<
     type Output = Vec2<T>;
 
     fn mul(self, rhs: &Vec2<T>) -> Self::Output {
-        Vec2::<T>::new(self.r.t[0].dot(&rhs), self.r.t[1].dot(&rhs))
+        Vec2::<T>::new(self.r.t[0].dot(rhs), self.r.t[1].dot(rhs))
     }
 }
 
@@ -184,6 +239,7 @@ impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Vec2<T>> for &Mat2<
 impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Vec2<T>> for &Mat2<T> {
     type Output = Vec2<T>;
 
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
     fn mul(self, rhs: Vec2<T>) -> Self::Output {
         self * &rhs
     }
@@ -232,3 +288,35 @@ impl<T: DivAssign<T> + Copy> DivAssign<T> for Mat2<T> {
 }
 
 pub type Mat2d = Mat2<f64>;
+
+#[test]
+#[allow(clippy::op_ref)]
+fn test_mat2_inverse() {
+    let m = Mat2::new(Vec2::new(1, 2), Vec2::new(3, 4));
+    assert_eq!(m.transpose().r, Mat2::from_cols(m.r.t[0], m.r.t[1]).r);
+    assert_eq!(m.transpose().r.t[0], Vec2::new(1, 3));
+    assert_eq!((m.trace(), m.determinant()), (5, -2));
+    assert_eq!((&m * &Mat2::identity()).r, m.r);
+    assert_eq!((m * Mat2::zero()).r, Mat2::<i32>::zero().r);
+
+    let rot = Mat2d::new_rot(0.3) * 2.0;
+    let product = &rot * &rot.inverse().unwrap();
+    for (row, expected) in product.r.t.iter().zip(Mat2d::identity().r.t) {
+        assert!((row - expected).length() < 1e-12);
+    }
+
+    let singular = Mat2d::new(Vec2d::new_raw(1e-9, 2e-9), Vec2d::new_raw(2e-9, 4e-9));
+    assert!(singular.inverse().is_none());
+    assert!((singular * 1e-9).inverse().is_none());
+    assert!(Mat2d::zero().inverse().is_none());
+    let small = Mat2d::identity() * 1e-9;
+    assert!((small.inverse().unwrap().r.t[1].t[1] - 1e9).abs() < 1e-3);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_mat2_serde() {
+    let m = Mat2::new(Vec2::new(1.0, 2.5), Vec2::new(-0.1, 4.0));
+    let copy: Mat2d = serde_json::from_str(&serde_json::to_string(&m).unwrap()).unwrap();
+    assert_eq!(copy.r, m.r);
+}
@@ -1,6 +1,8 @@
-pub use crate::vec3::*;
+use crate::traits::*;
+use crate::vec3::*;
 use std::ops::*;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Copy, Clone, Debug)]
 pub struct Mat3<T: Copy> {
     pub r: Vec3<Vec3<T>>,
@@ -12,6 +14,65 @@ impl<T: Copy> Mat3<T> {
             r: Vec3::<Vec3<T>>::new(row1, row2, row3),
         }
     }
+
+    pub fn from_cols(col1: Vec3<T>, col2: Vec3<T>, col3: Vec3<T>) -> Self {
+        Self::new(
+            Vec3::new(col1.t[0], col2.t[0], col3.t[0]),
+            Vec3::new(col1.t[1], col2.t[1], col3.t[1]),
+            Vec3::new(col1.t[2], col2.t[2], col3.t[2]),
+        )
+    }
+
+    pub fn transpose(&self) -> Self {
+        Self::from_cols(self.r.t[0], self.r.t[1], self.r.t[2])
+    }
+}
+
+impl<T: From<u8> + Copy> Mat3<T> {
+    pub fn zero() -> Self {
+        let zero = Vec3::new(T::from(0), T::from(0), T::from(0));
+        Self::new(zero, zero, zero)
+    }
+
+    pub fn identity() -> Self {
+        let (zero, one) = (T::from(0), T::from(1));
+        Self::new(
+            Vec3::new(one, zero, zero),
+            Vec3::new(zero, one, zero),
+            Vec3::new(zero, zero, one),
+        )
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Mat3<T> {
+    pub fn trace(&self) -> T {
+        self.r.t[0].t[0] + self.r.t[1].t[1] + self.r.t[2].t[2]
+    }
+}
+
+impl<T: Add<T, Output = T> + Sub<T, Output = T> + Mul<T, Output = T> + Copy> Mat3<T> {
+    pub fn determinant(&self) -> T {
+        self.r.t[0].dot(&self.r.t[1].cross(&self.r.t[2]))
+    }
+}
+
+impl Mat3<f64> {
+    pub fn inverse(&self) -> Option<Self> {
+        let scale = self
+            .r
+            .t
+            .iter()
+            .flat_map(|row| row.t)
+            .fold(0.0, |a: f64, b| a.max(b.abs()));
+        let determinant = self.determinant();
+        if determinant.abs() <= SINGULAR_TOLERANCE * scale * scale * scale {
+            return None;
+        }
+
+        // The columns of the inverse are orthogonal to two rows each
+        let [r0, r1, r2] = self.r.t;
+        Some(Self::from_cols(r1.cross(&r2), r2.cross(&r0), r0.cross(&r1)) * (1.0 / determinant))
+    }
 }
 
 impl<T: Neg<Output = T> + Copy> Neg for &Mat3<T> {
@@ -152,6 +213,7 @@ impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Mat3<T>> for Mat3<T>
 impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Mat3<T>> for &Mat3<T> {
     type Output = Mat3<T>;
 
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
     fn mul(self, rhs: Mat3<T>) -> Self::Output {
         self * &rhs
     }
@@ -160,6 +222,7 @@ impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Mat3<T>> for &Mat3<T
 impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Mat3<T>> for Mat3<T> {
     type Output = Mat3<T>;
 
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
     fn mul(mut self, rhs: &Mat3<T>) -> Self::Output {
         self = &self * rhs;
         self
@@ -178,9 +241,9 @@ impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Vec3<T>> for &Mat3<
 
     fn mul(self, rhs: &Vec3<T>) -> Self::Output {
         Vec3::<T>::new(
-            self.r.t[0].dot(&rhs),
-            self.r.t[1].dot(&rhs),
-            self.r.t[2].dot(&rhs),
+            self.r.t[0].dot(rhs),
+            self.r.t[1].dot(rhs),
+            self.r.t[2].dot(rhs),
         )
     }
 }
@@ -189,6 +252,7 @@ impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Vec3<T>> for &Mat3<
 impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Vec3<T>> for &Mat3<T> {
     type Output = Vec3<T>;
 
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
     fn mul(self, rhs: Vec3<T>) -> Self::Output {
         self * &rhs
     }
@@ -218,6 +282,7 @@ impl<T: MulAssign<T> + Copy> MulAssign<T> for Mat3<T> {
     fn mul_assign(&mut self, rhs: T) {
         self.r.t[0] *= rhs;
         self.r.t[1] *= rhs;
+        self.r.t[2] *= rhs;
     }
 }
 
@@ -233,7 +298,54 @@ impl<T: DivAssign<T> + Copy> DivAssign<T> for Mat3<T> {
     fn div_assign(&mut self, rhs: T) {
         self.r.t[0] /= rhs;
         self.r.t[1] /= rhs;
+        self.r.t[2] /= rhs;
     }
 }
 
 pub type Mat3d = Mat3<f64>;
+
+#[test]
+fn test_mat3_scalar_assign() {
+    // Every row is scaled, the last one included
+    let m = Mat3::new(Vec3::new(2, 0, 1), Vec3::new(1, 3, 2), Vec3::new(1, 1, 2));
+    assert_eq!((m * 3).r.t[2], Vec3::new(3, 3, 6));
+
+    let mut scaled = m;
+    scaled *= 4;
+    assert_eq!(scaled.r.t[2], Vec3::new(4, 4, 8));
+    scaled /= 2;
+    assert_eq!(scaled.r.t[2], Vec3::new(2, 2, 4));
+}
+
+#[test]
+#[allow(clippy::op_ref)]
+fn test_mat3_inverse() {
+    let m = Mat3::new(Vec3::new(2, 0, 1), Vec3::new(1, 3, 2), Vec3::new(1, 1, 2));
+    assert_eq!(m.transpose().r.t[0], Vec3::new(2, 1, 1));
+    assert_eq!(m.transpose().transpose().r, m.r);
+    assert_eq!((m.trace(), m.determinant()), (7, 6));
+    assert_eq!((&m * &Mat3::identity()).r, m.r);
+
+    let m = Mat3d::new(
+        Vec3d::new_raw(2.0, -1.0, 0.5),
+        Vec3d::new_raw(0.3, 4.0, 1.0),
+        Vec3d::new_raw(-1.0, 0.0, 3.0),
+    );
+    let product = &m * &m.inverse().unwrap();
+    for (row, expected) in product.r.t.iter().zip(Mat3d::identity().r.t) {
+        assert!((row - expected).length() < 1e-12);
+    }
+
+    let singular = Mat3d::from_cols(m.r.t[0], m.r.t[1], m.r.t[0] * 2.0 - m.r.t[1]);
+    assert!(singular.inverse().is_none());
+    assert!(Mat3d::zero().inverse().is_none());
+    assert!((Mat3d::identity() * 1e-6).inverse().is_some());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_mat3_serde() {
+    let m = Mat3::new(Vec3::new(1, 2, 3), Vec3::new(4, 5, 6), Vec3::new(7, 8, 9));
+    let copy: Mat3<i32> = serde_json::from_str(&serde_json::to_string(&m).unwrap()).unwrap();
+    assert_eq!(copy.r, m.r);
+}
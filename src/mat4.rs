@@ -0,0 +1,367 @@
+use crate::vec3::*;
+use crate::vec4::*;
+use std::ops::*;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug)]
+pub struct Mat4<T: Copy> {
+    pub r: Vec4<Vec4<T>>,
+}
+
+impl<T: Copy> Mat4<T> {
+    pub fn new(row1: Vec4<T>, row2: Vec4<T>, row3: Vec4<T>, row4: Vec4<T>) -> Self {
+        Self {
+            r: Vec4::<Vec4<T>>::new(row1, row2, row3, row4),
+        }
+    }
+}
+
+impl<T: Neg<Output = T> + Copy> Neg for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn neg(self) -> Self::Output {
+        Mat4::<T> { r: self.r.neg() }
+    }
+}
+
+impl<T: Neg<Output = T> + Copy> Neg for Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn neg(self) -> Self::Output {
+        Mat4::<T> { r: self.r.neg() }
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<&Mat4<T>> for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn add(self, rhs: &Mat4<T>) -> Self::Output {
+        Mat4::<T> { r: self.r + rhs.r }
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<Mat4<T>> for Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn add(mut self, rhs: Mat4<T>) -> Self::Output {
+        self.r = self.r + rhs.r;
+        self
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<&Mat4<T>> for Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn add(mut self, rhs: &Mat4<T>) -> Self::Output {
+        self.r = self.r + rhs.r;
+        self
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<Mat4<T>> for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn add(self, mut rhs: Mat4<T>) -> Self::Output {
+        rhs.r = self.r + rhs.r;
+        rhs
+    }
+}
+
+impl<'a, T: AddAssign<&'a T> + Copy> AddAssign<&'a Mat4<T>> for Mat4<T> {
+    fn add_assign(&mut self, rhs: &'a Self) {
+        self.r += &rhs.r;
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<&Mat4<T>> for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn sub(self, rhs: &Mat4<T>) -> Self::Output {
+        Mat4::<T> { r: self.r - rhs.r }
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<Mat4<T>> for Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn sub(mut self, rhs: Mat4<T>) -> Self::Output {
+        self.r = self.r - rhs.r;
+        self
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<&Mat4<T>> for Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn sub(mut self, rhs: &Mat4<T>) -> Self::Output {
+        self.r = self.r - rhs.r;
+        self
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<Mat4<T>> for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn sub(self, mut rhs: Mat4<T>) -> Self::Output {
+        rhs.r = self.r - rhs.r;
+        rhs
+    }
+}
+
+impl<'a, T: SubAssign<&'a T> + Copy> SubAssign<&'a Mat4<T>> for Mat4<T> {
+    fn sub_assign(&mut self, rhs: &'a Self) {
+        self.r -= &rhs.r;
+    }
+}
+
+impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Mat4<T>> for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn mul(self, rhs: &Mat4<T>) -> Self::Output {
+        let col = |j: usize| {
+            Vec4::<T>::new(
+                rhs.r.t[0].t[j],
+                rhs.r.t[1].t[j],
+                rhs.r.t[2].t[j],
+                rhs.r.t[3].t[j],
+            )
+        };
+        let (col0, col1, col2, col3) = (col(0), col(1), col(2), col(3));
+        let row = |i: usize| {
+            let row: &Vec4<T> = &self.r.t[i];
+            Vec4::<T>::new(
+                row.dot(&col0),
+                row.dot(&col1),
+                row.dot(&col2),
+                row.dot(&col3),
+            )
+        };
+
+        Mat4::<T>::new(row(0), row(1), row(2), row(3))
+    }
+}
+
+impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Mat4<T>> for Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn mul(mut self, rhs: Mat4<T>) -> Self::Output {
+        self = &self * &rhs;
+        self
+    }
+}
+
+impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Mat4<T>> for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
+    fn mul(self, rhs: Mat4<T>) -> Self::Output {
+        self * &rhs
+    }
+}
+
+impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Mat4<T>> for Mat4<T> {
+    type Output = Mat4<T>;
+
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
+    fn mul(mut self, rhs: &Mat4<T>) -> Self::Output {
+        self = &self * rhs;
+        self
+    }
+}
+
+impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> MulAssign<&Mat4<T>> for Mat4<T> {
+    fn mul_assign(&mut self, rhs: &Self) {
+        self.r = (*self * rhs).r;
+    }
+}
+
+// Multiplication Matrix * Vector
+impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<&Vec4<T>> for &Mat4<T> {
+    type Output = Vec4<T>;
+
+    fn mul(self, rhs: &Vec4<T>) -> Self::Output {
+        Vec4::<T>::new(
+            self.r.t[0].dot(rhs),
+            self.r.t[1].dot(rhs),
+            self.r.t[2].dot(rhs),
+            self.r.t[3].dot(rhs),
+        )
+    }
+}
+
+// Multiplication Matrix * Vector - convenience function
+impl<T: Mul<T, Output = T> + Add<T, Output = T> + Copy> Mul<Vec4<T>> for &Mat4<T> {
+    type Output = Vec4<T>;
+
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
+    fn mul(self, rhs: Vec4<T>) -> Self::Output {
+        self * &rhs
+    }
+}
+
+// Multiplication Matrix * Scalar
+impl<T: Mul<T, Output = T> + Copy> Mul<T> for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn mul(self, rhs: T) -> Self::Output {
+        Mat4::<T> {
+            r: Vec4::new(
+                self.r.t[0] * rhs,
+                self.r.t[1] * rhs,
+                self.r.t[2] * rhs,
+                self.r.t[3] * rhs,
+            ),
+        }
+    }
+}
+
+impl<T: MulAssign<T> + Copy> Mul<T> for Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn mul(mut self, rhs: T) -> Self::Output {
+        self *= rhs;
+        self
+    }
+}
+
+impl<T: MulAssign<T> + Copy> MulAssign<T> for Mat4<T> {
+    fn mul_assign(&mut self, rhs: T) {
+        self.r.t[0] *= rhs;
+        self.r.t[1] *= rhs;
+        self.r.t[2] *= rhs;
+        self.r.t[3] *= rhs;
+    }
+}
+
+impl<T: Div<T, Output = T> + Copy> Div<T> for &Mat4<T> {
+    type Output = Mat4<T>;
+
+    fn div(self, rhs: T) -> Self::Output {
+        Mat4::<T>::new(
+            &self.r.t[0] / rhs,
+            &self.r.t[1] / rhs,
+            &self.r.t[2] / rhs,
+            &self.r.t[3] / rhs,
+        )
+    }
+}
+
+impl<T: DivAssign<T> + Copy> DivAssign<T> for Mat4<T> {
+    fn div_assign(&mut self, rhs: T) {
+        self.r.t[0] /= rhs;
+        self.r.t[1] /= rhs;
+        self.r.t[2] /= rhs;
+        self.r.t[3] /= rhs;
+    }
+}
+
+pub type Mat4d = Mat4<f64>;
+
+impl<T: From<u8> + Copy> Mat4<T> {
+    pub fn zero() -> Self {
+        let zero = Vec4::new(T::from(0), T::from(0), T::from(0), T::from(0));
+        Self::new(zero, zero, zero, zero)
+    }
+
+    pub fn identity() -> Self {
+        let (zero, one) = (T::from(0), T::from(1));
+        Self::new(
+            Vec4::new(one, zero, zero, zero),
+            Vec4::new(zero, one, zero, zero),
+            Vec4::new(zero, zero, one, zero),
+            Vec4::new(zero, zero, zero, one),
+        )
+    }
+}
+
+impl Mat4d {
+    pub fn new_translation(offset: &Vec3d) -> Self {
+        Self::new(
+            Vec4d::new_raw(1.0, 0.0, 0.0, offset.t[0]),
+            Vec4d::new_raw(0.0, 1.0, 0.0, offset.t[1]),
+            Vec4d::new_raw(0.0, 0.0, 1.0, offset.t[2]),
+            Vec4d::new_raw(0.0, 0.0, 0.0, 1.0),
+        )
+    }
+
+    // Right handed, looking down -z, depth is mapped to [-1, 1]
+    pub fn new_perspective(fov: f64, aspect: f64, near: f64, far: f64) -> Self {
+        let f = 1.0 / (0.5 * fov).tan();
+        Self::new(
+            Vec4d::new_raw(f / aspect, 0.0, 0.0, 0.0),
+            Vec4d::new_raw(0.0, f, 0.0, 0.0),
+            Vec4d::new_raw(
+                0.0,
+                0.0,
+                (far + near) / (near - far),
+                2.0 * far * near / (near - far),
+            ),
+            Vec4d::new_raw(0.0, 0.0, -1.0, 0.0),
+        )
+    }
+}
+
+#[test]
+#[allow(clippy::op_ref)]
+fn test_mat4_operators() {
+    let a = Mat4::new(
+        Vec4::new(1, 2, 3, 4),
+        Vec4::new(0, 1, 0, 0),
+        Vec4::new(0, 0, 2, 0),
+        Vec4::new(1, 0, 0, 1),
+    );
+    let b = Mat4::identity();
+
+    assert_eq!((&a * &b).r, a.r);
+    assert_eq!((b * a).r, a.r);
+    assert_eq!(
+        (&a * &a).r,
+        Vec4::new(
+            Vec4::new(5, 4, 9, 8),
+            Vec4::new(0, 1, 0, 0),
+            Vec4::new(0, 0, 4, 0),
+            Vec4::new(2, 2, 3, 5),
+        )
+    );
+    assert_eq!(&a * Vec4::new(1, 1, 1, 1), Vec4::new(10, 1, 2, 2));
+    assert_eq!((a + b - b).r, a.r);
+    assert_eq!((-&a).r.t[2], Vec4::new(0, 0, -2, 0));
+
+    let mut c = a;
+    c *= 3;
+    assert_eq!(c.r.t[3], Vec4::new(3, 0, 0, 3));
+    c /= 3;
+    assert_eq!(c.r, a.r);
+    c *= &b;
+    c += &a;
+    c -= &a;
+    assert_eq!((&c * 2).r, (a * 2).r);
+    assert_eq!((&c / 1).r, a.r);
+
+    let point = Vec3d::new_raw(1.0, -2.0, 0.5);
+    let offset = Vec3d::new_raw(3.0, 4.0, 5.0);
+    let moved = &Mat4d::new_translation(&offset) * point.extend(1.0);
+    assert_eq!(moved.truncate(), point + offset);
+    let direction = &Mat4d::new_translation(&offset) * point.extend(0.0);
+    assert_eq!(direction.truncate(), point);
+
+    // Points on the near and far planes end up at depth -1 and 1
+    let projection = Mat4d::new_perspective(std::f64::consts::FRAC_PI_2, 2.0, 1.0, 10.0);
+    let near = (&projection * Vec4d::new_raw(2.0, 1.0, -1.0, 1.0)).project();
+    let far = (&projection * Vec4d::new_raw(0.0, 0.0, -10.0, 1.0)).project();
+    assert!((near - Vec3d::new_raw(1.0, 1.0, -1.0)).length() < 1e-12);
+    assert!((far.t[2] - 1.0).abs() < 1e-12);
+    assert_eq!(&Mat4d::identity() * moved, moved);
+}
+
+#[test]
+fn test_mat4_identity() {
+    let identity = Mat4::<i32>::identity();
+    for i in 0..4 {
+        for j in 0..4 {
+            assert_eq!(identity.r.t[i].t[j], i32::from(i == j));
+        }
+    }
+    assert_eq!((Mat4::<f32>::identity() * 2.0).r.t[3].t[3], 2.0);
+    assert_eq!((Mat4::<i64>::zero() * Mat4::identity()).r, Mat4::zero().r);
+}
@@ -0,0 +1,231 @@
+use crate::frozen_kd_tree::*;
+use crate::kd_tree::*;
+use crate::kd_tree_traits::*;
+use crate::ray_box::*;
+use std::ops::{Deref, DerefMut};
+
+#[cfg(test)]
+use crate::test_shapes::*;
+
+// A KdTree whose nodes are split at their center in every dimension at once.
+// All queries and modifications are those of KdTree.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(try_from = "OrthtreeData<BoundingBox, Content>")
+)]
+pub struct Orthtree<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>(
+    KdTree<BoundingBox, Content>,
+);
+
+// Deserialized trees are only accepted when they still split into orthants
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct OrthtreeData<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>(
+    KdTree<BoundingBox, Content>,
+);
+
+#[cfg(feature = "serde")]
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    TryFrom<OrthtreeData<BoundingBox, Content>> for Orthtree<BoundingBox, Content>
+{
+    type Error = &'static str;
+
+    fn try_from(data: OrthtreeData<BoundingBox, Content>) -> Result<Self, Self::Error> {
+        if data.0.get_config().subdivision != KdTreeSubdivision::Orthants {
+            return Err("subdivision isn't Orthants");
+        }
+        Ok(Orthtree(data.0))
+    }
+}
+
+pub type QuadTree<Content> = Orthtree<BoundingBox2d, Content>;
+pub type Octree<Content> = Orthtree<BoundingBox3d, Content>;
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    Orthtree<BoundingBox, Content>
+{
+    pub fn new() -> Self {
+        Self::with_config(KdTreeConfig::default())
+    }
+
+    // The subdivision of the configuration is ignored
+    pub fn with_config(config: KdTreeConfig<BoundingBox>) -> Self {
+        Orthtree(KdTree::with_config(KdTreeConfig {
+            subdivision: KdTreeSubdivision::Orthants,
+            ..config
+        }))
+    }
+
+    pub fn build(contents: Vec<Content>) -> Self {
+        Self::build_with_config(contents, KdTreeConfig::default())
+    }
+
+    pub fn build_with_config(contents: Vec<Content>, config: KdTreeConfig<BoundingBox>) -> Self {
+        Orthtree(KdTree::build_orthants(contents, config))
+    }
+
+    pub fn into_kd_tree(self) -> KdTree<BoundingBox, Content> {
+        self.0
+    }
+
+    pub fn freeze(self) -> FrozenKdTree<BoundingBox, Content> {
+        self.0.freeze()
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> Default
+    for Orthtree<BoundingBox, Content>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> Deref
+    for Orthtree<BoundingBox, Content>
+{
+    type Target = KdTree<BoundingBox, Content>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> DerefMut
+    for Orthtree<BoundingBox, Content>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> IntoIterator
+    for Orthtree<BoundingBox, Content>
+{
+    type Item = Content;
+    type IntoIter = KdTreeIntoIterator<Content>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a, BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> IntoIterator
+    for &'a Orthtree<BoundingBox, Content>
+{
+    type Item = &'a Content;
+    type IntoIter = KdTreeIterator<'a, Content>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> FromIterator<Content>
+    for Orthtree<BoundingBox, Content>
+{
+    fn from_iter<I: IntoIterator<Item = Content>>(iter: I) -> Self {
+        Self::build(iter.into_iter().collect())
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> Extend<Content>
+    for Orthtree<BoundingBox, Content>
+{
+    fn extend<I: IntoIterator<Item = Content>>(&mut self, iter: I) {
+        self.0.extend(iter)
+    }
+}
+
+#[test]
+fn test_quad_tree() {
+    use std::collections::HashSet;
+
+    let points: Vec<Point2d> = (0..1500)
+        .map(|i| {
+            let i = i as f64;
+            Point2d::new_raw((0.37 * i).sin() * 7.0 + 2.0, (0.73 * i).cos() * 4.0 - 1.0)
+        })
+        .collect();
+    let kd_tree = KdTree::build(points.clone());
+
+    let mut added = QuadTree::new();
+    added.extend(points.iter().cloned());
+    let built: QuadTree<Point2d> = points.iter().cloned().collect();
+
+    let filter = BoundingBox2d::new(Point2d::new_raw(-1.0, -2.0), Point2d::new_raw(3.5, 0.5));
+    let expected = kd_tree.get_intersection(&filter);
+    assert!(expected.len() > 50);
+
+    for quad_tree in [added, built] {
+        // Every split, including the growth of the root, creates four children
+        let stats = quad_tree.stats();
+        assert!(stats.depth > 2);
+        assert_eq!((stats.node_count - 1) % 4, 0);
+        assert_eq!(quad_tree.len(), points.len());
+
+        assert_eq!(quad_tree.get_intersection(&filter), expected);
+        assert_eq!(quad_tree.get_in_region(&filter), expected);
+        for i in 0..10 {
+            let query = Point2d::new_raw(i as f64 - 3.0, 0.3 * i as f64 - 2.0);
+            assert_eq!(quad_tree.k_nearest(&query, 4), kd_tree.k_nearest(&query, 4));
+        }
+
+        let frozen = quad_tree.freeze();
+        let contents: HashSet<Point2d> = frozen.get_intersection(&filter);
+        assert_eq!(contents, expected);
+    }
+}
+
+#[test]
+fn test_octree() {
+    let spheres = get_test_spheres();
+    let kd_tree = KdTree::<BoundingBox3d, _>::build(spheres.clone());
+    let mut octree = Octree::with_config(KdTreeConfig {
+        leaf_capacity: 2,
+        ..Default::default()
+    });
+    for sphere in &spheres {
+        octree.add(sphere.clone());
+    }
+    assert_eq!(octree.get_config().subdivision, KdTreeSubdivision::Orthants);
+    assert_eq!((octree.stats().node_count - 1) % 8, 0);
+
+    for i in 0..20 {
+        let cray = ConstrainedRay3d {
+            ray: Ray3d::new(
+                Point3d::new_raw(-5.0, 0.17 * i as f64, 0.3),
+                Point3d::new_raw(1.0, 0.01 * i as f64, 0.02),
+            ),
+            range: (0.0, 100.0),
+        };
+        assert_eq!(
+            octree.get_closest_hit(&hit_test_sphere, &cray),
+            kd_tree.get_closest_hit(&hit_test_sphere, &cray)
+        );
+    }
+
+    assert!(octree.remove(&spheres[3]));
+    assert_eq!(octree.len(), spheres.len() - 1);
+    let octree = Octree::build(spheres.clone());
+    assert_eq!(octree.into_kd_tree().len(), spheres.len());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_orthtree_serde() {
+    let points: Vec<Point2d> = (0..50)
+        .map(|i| Point2d::new_raw((0.3 * i as f64).sin(), 0.1 * i as f64))
+        .collect();
+    let quad_tree = QuadTree::build(points.clone());
+    let mut value = serde_json::to_value(&quad_tree).unwrap();
+    let copy: QuadTree<Point2d> = serde_json::from_value(value.clone()).unwrap();
+    assert_eq!(copy.len(), points.len());
+    assert_eq!(copy.get_config().subdivision, KdTreeSubdivision::Orthants);
+
+    // A tree split in halves is no Orthtree
+    value["config"]["subdivision"] = serde_json::json!("Halving");
+    assert!(serde_json::from_value::<QuadTree<Point2d>>(value.clone()).is_err());
+    assert!(serde_json::from_value::<KdTree<BoundingBox2d, Point2d>>(value).is_ok());
+}
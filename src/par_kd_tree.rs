@@ -0,0 +1,171 @@
+use crate::kd_tree::*;
+use crate::kd_tree_traits::*;
+#[cfg(test)]
+use crate::ray_box::*;
+#[cfg(test)]
+use crate::test_shapes::*;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::hash::Hash;
+
+const PARALLEL_BUILD_MIN_CONTENTS: usize = 1024;
+
+impl<BoundingBox, Content> KdTree<BoundingBox, Content>
+where
+    BoundingBox: BoundingBoxTrait + Send + Sync,
+    Content: KdTreeContent<BoundingBox>,
+{
+    fn par_build_node(
+        enclosure: BoundingBox,
+        contents: BuildContents<BoundingBox>,
+        strategy: Option<KdTreeSplitStrategy>,
+        config: &KdTreeConfig<BoundingBox>,
+        depth: usize,
+    ) -> KdNode<BoundingBox> {
+        // Small subtrees are cheaper to build than to hand to another thread
+        if contents.len() < PARALLEL_BUILD_MIN_CONTENTS {
+            return Self::build_node(enclosure, contents, strategy, config, depth);
+        }
+
+        let (mut node, children) = Self::split_node(enclosure, contents, strategy, config, depth);
+        node.children = children
+            .into_par_iter()
+            .map(|(child, child_contents)| {
+                Self::par_build_node(child, child_contents, strategy, config, depth + 1)
+            })
+            .collect();
+        node
+    }
+
+    // Same tree as build, with the subtrees built on several threads
+    pub fn par_build(contents: Vec<Content>) -> Self {
+        Self::par_build_with_config(
+            contents,
+            KdTreeSplitStrategy::SurfaceAreaHeuristic,
+            KdTreeConfig::default(),
+        )
+    }
+
+    pub fn par_build_with_config(
+        contents: Vec<Content>,
+        strategy: KdTreeSplitStrategy,
+        config: KdTreeConfig<BoundingBox>,
+    ) -> Self {
+        Self::build_with_root(contents, config, |enclosure, enclosures, config| {
+            Self::par_build_node(enclosure, enclosures, Some(strategy), config, 0)
+        })
+    }
+}
+
+impl<BoundingBox, Content> KdTree<BoundingBox, Content>
+where
+    BoundingBox: BoundingBoxTrait + Sync,
+    Content: KdTreeContent<BoundingBox> + Sync,
+{
+    // Results are in the same order as the filters
+    pub fn par_get_intersection(&self, filters: &[BoundingBox]) -> Vec<HashSet<Content>>
+    where
+        Content: Clone + Eq + Hash + Send,
+    {
+        filters
+            .par_iter()
+            .map(|filter| self.get_intersection(filter))
+            .collect()
+    }
+}
+
+impl<BoundingBox, Content> KdTree<BoundingBox, Content>
+where
+    BoundingBox: HittableBoundingBoxTrait + Sync,
+    BoundingBox::Ray: Sync,
+    Content: KdTreeContent<BoundingBox> + Sync,
+{
+    pub fn par_get_closest_hit_handle<F>(
+        &self,
+        fun: &F,
+        crays: &[BoundingBox::Ray],
+    ) -> Vec<Option<KdTreeHandle>>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64> + Sync,
+    {
+        crays
+            .par_iter()
+            .map(|cray| self.get_closest_hit_handle(fun, cray))
+            .collect()
+    }
+
+    // Results are in the same order as the rays
+    pub fn par_get_closest_hit<F>(
+        &self,
+        fun: &F,
+        crays: &[BoundingBox::Ray],
+    ) -> Vec<Option<Content>>
+    where
+        F: Fn(&Content, &BoundingBox::Ray) -> Option<f64> + Sync,
+        Content: Clone + Send,
+    {
+        crays
+            .par_iter()
+            .map(|cray| self.get_closest_hit(fun, cray))
+            .collect()
+    }
+}
+
+#[test]
+fn test_kd_tree_parallel() {
+    fn assert_sync<T: Sync>(_: &T) {}
+
+    // Enough contents for the build to run on several threads
+    let spheres: Vec<TestSphere> = (0..4000)
+        .map(|i| TestSphere {
+            center: Point3d::new_raw(
+                (i % 20) as f64,
+                ((i / 20) % 20) as f64,
+                (i / 400) as f64 * 1.7,
+            ),
+        })
+        .collect();
+    let kd_tree = KdTree::<BoundingBox3d, _>::par_build(spheres.clone());
+    assert_sync(&kd_tree);
+    assert_eq!(kd_tree.len(), 4000);
+    assert_eq!(
+        kd_tree.stats(),
+        KdTree::<BoundingBox3d, _>::build(spheres.clone()).stats()
+    );
+
+    let crays: Vec<ConstrainedRay3d> = (0..200)
+        .map(|i| ConstrainedRay3d {
+            ray: Ray3d::new(
+                Point3d::new_raw(-5.0, 0.1 * i as f64, 0.13 * i as f64),
+                Point3d::new_raw(1.0, 0.02, 0.01),
+            ),
+            range: (0.0, 100.0),
+        })
+        .collect();
+    let hits = kd_tree.par_get_closest_hit(&hit_test_sphere, &crays);
+    let handles = kd_tree.par_get_closest_hit_handle(&hit_test_sphere, &crays);
+    assert_eq!(hits.len(), crays.len());
+    assert!(hits.iter().any(|hit| hit.is_some()));
+    for ((cray, hit), handle) in crays.iter().zip(&hits).zip(&handles) {
+        assert_eq!(hit, &kd_tree.get_closest_hit(&hit_test_sphere, cray));
+        assert_eq!(hit.as_ref(), handle.and_then(|handle| kd_tree.get(handle)));
+    }
+
+    let filters: Vec<BoundingBox3d> = (0..50)
+        .map(|i| BoundingBox3d {
+            u: Point3d::new_raw(0.3 * i as f64, 0.2 * i as f64, 0.5 * i as f64),
+            v: Point3d::new_raw(
+                0.3 * i as f64 + 2.0,
+                0.2 * i as f64 + 3.0,
+                0.5 * i as f64 + 1.0,
+            ),
+        })
+        .collect();
+    let intersections = kd_tree.par_get_intersection(&filters);
+    for (filter, intersection) in filters.iter().zip(&intersections) {
+        assert_eq!(intersection, &kd_tree.get_intersection(filter));
+    }
+    assert!(intersections
+        .iter()
+        .any(|intersection| intersection.len() > 10));
+}
@@ -0,0 +1,419 @@
+use crate::mat3::*;
+use crate::ray_box::*;
+use crate::vec3::*;
+use std::ops::*;
+
+// Below this the rotation axis is not well defined
+const AXIS_TOLERANCE: f64 = 1e-12;
+
+// Above this dot product slerp falls back to nlerp
+const SLERP_THRESHOLD: f64 = 0.9995;
+
+// w + v.x * i + v.y * j + v.z * k
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quat<T: Copy> {
+    pub w: T,
+    pub v: Vec3<T>,
+}
+
+pub type Quatd = Quat<f64>;
+
+// Axes in the order the rotations are applied, about the fixed world axes.
+// XYZ rotates about x first, so its matrix is Rz * Ry * Rx.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EulerOrder {
+    XYZ,
+    XZY,
+    YXZ,
+    YZX,
+    ZXY,
+    ZYX,
+}
+
+impl EulerOrder {
+    fn get_axes(&self) -> [usize; 3] {
+        match self {
+            EulerOrder::XYZ => [0, 1, 2],
+            EulerOrder::XZY => [0, 2, 1],
+            EulerOrder::YXZ => [1, 0, 2],
+            EulerOrder::YZX => [1, 2, 0],
+            EulerOrder::ZXY => [2, 0, 1],
+            EulerOrder::ZYX => [2, 1, 0],
+        }
+    }
+}
+
+impl<T: Copy> Quat<T> {
+    pub fn new(w: T, v: Vec3<T>) -> Self {
+        Self { w, v }
+    }
+}
+
+impl<T: From<u8> + Copy> Quat<T> {
+    pub fn identity() -> Self {
+        let zero = T::from(0);
+        Self::new(T::from(1), Vec3::new(zero, zero, zero))
+    }
+}
+
+impl<T: Neg<Output = T> + Copy> Quat<T> {
+    pub fn conjugate(&self) -> Self {
+        Self::new(self.w, -self.v)
+    }
+}
+
+impl<T: Add<T, Output = T> + Mul<T, Output = T> + Copy> Quat<T> {
+    pub fn dot(&self, other: &Self) -> T {
+        self.w * other.w + self.v.dot(&other.v)
+    }
+
+    pub fn squared_length(&self) -> T {
+        self.dot(self)
+    }
+}
+
+impl Norm for Quat<f64> {
+    type Length = f64;
+    fn length(&self) -> Self::Length {
+        self.squared_length().sqrt()
+    }
+}
+
+impl<T: Neg<Output = T> + Copy> Neg for &Quat<T> {
+    type Output = Quat<T>;
+
+    fn neg(self) -> Self::Output {
+        Quat::new(-self.w, -self.v)
+    }
+}
+
+impl<T: Neg<Output = T> + Copy> Neg for Quat<T> {
+    type Output = Quat<T>;
+
+    fn neg(self) -> Self::Output {
+        Quat::new(-self.w, -self.v)
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<&Quat<T>> for &Quat<T> {
+    type Output = Quat<T>;
+
+    fn add(self, rhs: &Quat<T>) -> Self::Output {
+        Quat::new(self.w + rhs.w, self.v + rhs.v)
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<Quat<T>> for Quat<T> {
+    type Output = Quat<T>;
+
+    fn add(self, rhs: Quat<T>) -> Self::Output {
+        Quat::new(self.w + rhs.w, self.v + rhs.v)
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<&Quat<T>> for &Quat<T> {
+    type Output = Quat<T>;
+
+    fn sub(self, rhs: &Quat<T>) -> Self::Output {
+        Quat::new(self.w - rhs.w, self.v - rhs.v)
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<Quat<T>> for Quat<T> {
+    type Output = Quat<T>;
+
+    fn sub(self, rhs: Quat<T>) -> Self::Output {
+        Quat::new(self.w - rhs.w, self.v - rhs.v)
+    }
+}
+
+// Hamilton product, rotating by the result applies rhs first
+impl<T: Add<T, Output = T> + Sub<T, Output = T> + Mul<T, Output = T> + Copy> Mul<&Quat<T>>
+    for &Quat<T>
+{
+    type Output = Quat<T>;
+
+    fn mul(self, rhs: &Quat<T>) -> Self::Output {
+        Quat::new(
+            self.w * rhs.w - self.v.dot(&rhs.v),
+            rhs.v * self.w + self.v * rhs.w + self.v.cross(&rhs.v),
+        )
+    }
+}
+
+impl<T: Add<T, Output = T> + Sub<T, Output = T> + Mul<T, Output = T> + Copy> Mul<Quat<T>>
+    for Quat<T>
+{
+    type Output = Quat<T>;
+
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
+    fn mul(self, rhs: Quat<T>) -> Self::Output {
+        &self * &rhs
+    }
+}
+
+impl<T: Add<T, Output = T> + Sub<T, Output = T> + Mul<T, Output = T> + Copy> MulAssign<&Quat<T>>
+    for Quat<T>
+{
+    fn mul_assign(&mut self, rhs: &Self) {
+        *self = &*self * rhs;
+    }
+}
+
+// Multiplication Quaternion * Scalar
+impl<T: Mul<T, Output = T> + Copy> Mul<T> for &Quat<T> {
+    type Output = Quat<T>;
+
+    fn mul(self, rhs: T) -> Self::Output {
+        Quat::new(self.w * rhs, self.v * rhs)
+    }
+}
+
+impl<T: Mul<T, Output = T> + Copy> Mul<T> for Quat<T> {
+    type Output = Quat<T>;
+
+    fn mul(self, rhs: T) -> Self::Output {
+        Quat::new(self.w * rhs, self.v * rhs)
+    }
+}
+
+impl Quatd {
+    pub fn get_normalized(&self) -> Self {
+        self * (1.0 / self.length())
+    }
+
+    pub fn normalize(&mut self) {
+        *self = self.get_normalized();
+    }
+
+    // Rotation by angle radians about the axis, counterclockwise when looking against it
+    pub fn from_axis_angle(axis: &Direction3d, angle: f64) -> Self {
+        let (sin, cos) = (0.5 * angle).sin_cos();
+        Self::new(cos, axis.get_normalized() * sin)
+    }
+
+    // Angle in [0, 2 pi], the axis is x for rotations close to the identity
+    pub fn to_axis_angle(&self) -> (Direction3d, f64) {
+        let q = self.get_normalized();
+        let sin = q.v.length();
+        let angle = 2.0 * sin.atan2(q.w);
+        if sin < AXIS_TOLERANCE {
+            return (Direction3d::new_raw(1.0, 0.0, 0.0), angle);
+        }
+        (&q.v / sin, angle)
+    }
+
+    // Only valid for unit quaternions
+    pub fn rotate(&self, vector: &Vec3d) -> Vec3d {
+        let t = self.v.cross(vector) * 2.0;
+        vector + t * self.w + self.v.cross(&t)
+    }
+
+    pub fn to_mat3(&self) -> Mat3d {
+        let [x, y, z] = self.v.t;
+        let w = self.w;
+        Mat3d::new(
+            Vec3d::new_raw(
+                1.0 - 2.0 * (y * y + z * z),
+                2.0 * (x * y - w * z),
+                2.0 * (x * z + w * y),
+            ),
+            Vec3d::new_raw(
+                2.0 * (x * y + w * z),
+                1.0 - 2.0 * (x * x + z * z),
+                2.0 * (y * z - w * x),
+            ),
+            Vec3d::new_raw(
+                2.0 * (x * z - w * y),
+                2.0 * (y * z + w * x),
+                1.0 - 2.0 * (x * x + y * y),
+            ),
+        )
+    }
+
+    // The matrix has to be a rotation, the result is normalized
+    pub fn from_mat3(matrix: &Mat3d) -> Self {
+        let m = matrix.r.t.map(|row| row.t);
+        let trace = matrix.trace();
+
+        // Start from the largest component to stay away from divisions by small numbers
+        let result = if trace > 0.0 {
+            let s = 2.0 * (1.0 + trace).sqrt();
+            Self::new(
+                0.25 * s,
+                Vec3d::new_raw(
+                    (m[2][1] - m[1][2]) / s,
+                    (m[0][2] - m[2][0]) / s,
+                    (m[1][0] - m[0][1]) / s,
+                ),
+            )
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = 2.0 * (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt();
+            Self::new(
+                (m[2][1] - m[1][2]) / s,
+                Vec3d::new_raw(0.25 * s, (m[0][1] + m[1][0]) / s, (m[0][2] + m[2][0]) / s),
+            )
+        } else if m[1][1] > m[2][2] {
+            let s = 2.0 * (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt();
+            Self::new(
+                (m[0][2] - m[2][0]) / s,
+                Vec3d::new_raw((m[0][1] + m[1][0]) / s, 0.25 * s, (m[1][2] + m[2][1]) / s),
+            )
+        } else {
+            let s = 2.0 * (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt();
+            Self::new(
+                (m[1][0] - m[0][1]) / s,
+                Vec3d::new_raw((m[0][2] + m[2][0]) / s, (m[1][2] + m[2][1]) / s, 0.25 * s),
+            )
+        };
+        result.get_normalized()
+    }
+
+    // Angles are indexed by axis, angles.t[0] is the rotation about x
+    pub fn from_euler(angles: &Vec3d, order: EulerOrder) -> Self {
+        let mut result = Self::identity();
+        for axis in order.get_axes() {
+            let mut direction = Direction3d::new_raw(0.0, 0.0, 0.0);
+            direction.t[axis] = 1.0;
+            result = Self::from_axis_angle(&direction, angles.t[axis]) * result;
+        }
+        result
+    }
+
+    // The middle angle is in [-pi/2, pi/2], in gimbal lock the last angle is 0
+    pub fn to_euler(&self, order: EulerOrder) -> Vec3d {
+        let m = self.get_normalized().to_mat3().r.t.map(|row| row.t);
+        let [i, j, k] = order.get_axes();
+        let sign = if (j + 3 - i) % 3 == 1 { 1.0 } else { -1.0 };
+
+        let mut angles = Vec3d::new_raw(0.0, 0.0, 0.0);
+        let sin_middle = -sign * m[k][i];
+        let cos_middle = m[k][j].hypot(m[k][k]);
+        angles.t[j] = sin_middle.atan2(cos_middle);
+        if cos_middle > AXIS_TOLERANCE {
+            angles.t[i] = (sign * m[k][j]).atan2(m[k][k]);
+            angles.t[k] = (sign * m[j][i]).atan2(m[i][i]);
+        } else {
+            angles.t[i] = (-sign * m[j][k]).atan2(m[j][j]);
+        }
+        angles
+    }
+
+    // Normalized linear interpolation along the shorter arc, not constant speed
+    pub fn nlerp(&self, other: &Self, t: f64) -> Self {
+        let other = if self.dot(other) < 0.0 {
+            -other
+        } else {
+            *other
+        };
+        (self * (1.0 - t) + other * t).get_normalized()
+    }
+
+    // Constant speed interpolation along the shorter arc
+    pub fn slerp(&self, other: &Self, t: f64) -> Self {
+        let mut dot = self.dot(other);
+        let other = if dot < 0.0 {
+            dot = -dot;
+            -other
+        } else {
+            *other
+        };
+        if dot > SLERP_THRESHOLD {
+            return self.nlerp(&other, t);
+        }
+
+        let theta = dot.acos();
+        let sin = theta.sin();
+        let a = ((1.0 - t) * theta).sin() / sin;
+        let b = (t * theta).sin() / sin;
+        (self * a + other * b).get_normalized()
+    }
+}
+
+#[test]
+#[allow(clippy::op_ref)]
+fn test_quat() {
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    let is_close = |a: &Vec3d, b: &Vec3d| (a - b).length() < 1e-9;
+    let x = Vec3d::new_raw(1.0, 0.0, 0.0);
+    let y = Vec3d::new_raw(0.0, 1.0, 0.0);
+    let z = Vec3d::new_raw(0.0, 0.0, 1.0);
+
+    let q = Quatd::from_axis_angle(&z, FRAC_PI_2);
+    assert!(is_close(&q.rotate(&x), &y));
+    assert!(is_close(&q.conjugate().rotate(&y), &x));
+    assert!(is_close(&(q * q).rotate(&x), &-x));
+    assert_eq!(
+        Quat::<i32>::identity() * Quat::new(1, Vec3::new(2, 3, 4)),
+        Quat::new(1, Vec3::new(2, 3, 4))
+    );
+
+    // i * j = k
+    let i = Quat::new(0, Vec3::new(1, 0, 0));
+    let j = Quat::new(0, Vec3::new(0, 1, 0));
+    assert_eq!(&i * &j, Quat::new(0, Vec3::new(0, 0, 1)));
+    assert_eq!(j * i, Quat::new(0, Vec3::new(0, 0, -1)));
+
+    let mut q = Quat::new(1.0, Vec3d::new_raw(2.0, -3.0, 0.5));
+    q.normalize();
+    assert!((q.length() - 1.0).abs() < 1e-12);
+    let (axis, angle) = q.to_axis_angle();
+    let back = Quatd::from_axis_angle(&axis, angle);
+    assert!((back.dot(&q) - 1.0).abs() < 1e-12);
+
+    // Matrix and quaternion rotate the same way and convert both ways
+    let vector = Vec3d::new_raw(0.3, -1.2, 2.0);
+    let matrix = q.to_mat3();
+    assert!(is_close(&(&matrix * &vector), &q.rotate(&vector)));
+    assert!((matrix.determinant() - 1.0).abs() < 1e-12);
+    for p in [
+        q,
+        -q,
+        Quatd::from_axis_angle(&x, PI),
+        Quatd::from_axis_angle(&y, 3.0),
+    ] {
+        let back = Quatd::from_mat3(&p.to_mat3());
+        assert!((back.dot(&p).abs() - 1.0).abs() < 1e-12);
+    }
+
+    let angles = Vec3d::new_raw(0.3, -0.7, 1.2);
+    for order in [
+        EulerOrder::XYZ,
+        EulerOrder::XZY,
+        EulerOrder::YXZ,
+        EulerOrder::YZX,
+        EulerOrder::ZXY,
+        EulerOrder::ZYX,
+    ] {
+        let [a, b, c] = order.get_axes();
+        let mut expected = vector;
+        for axis in [a, b, c] {
+            let mut direction = Vec3d::new_raw(0.0, 0.0, 0.0);
+            direction.t[axis] = 1.0;
+            expected = Quatd::from_axis_angle(&direction, angles.t[axis]).rotate(&expected);
+        }
+
+        let q = Quatd::from_euler(&angles, order);
+        assert!(is_close(&q.rotate(&vector), &expected));
+        assert!(is_close(&q.to_euler(order), &angles));
+
+        // In gimbal lock only the rotation is recovered
+        let mut locked = angles;
+        locked.t[b] = FRAC_PI_2;
+        let q = Quatd::from_euler(&locked, order);
+        let back = Quatd::from_euler(&q.to_euler(order), order);
+        assert!(is_close(&back.rotate(&vector), &q.rotate(&vector)));
+    }
+
+    let a = Quatd::from_axis_angle(&z, 0.2);
+    let b = Quatd::from_axis_angle(&z, 1.4);
+    let halfway = Quatd::from_axis_angle(&z, 0.8);
+    assert!((a.slerp(&b, 0.5).dot(&halfway) - 1.0).abs() < 1e-12);
+    assert!((a.slerp(&-b, 0.25).dot(&Quatd::from_axis_angle(&z, 0.5)) - 1.0).abs() < 1e-12);
+    assert!((a.nlerp(&b, 0.5).dot(&halfway) - 1.0).abs() < 1e-12);
+    assert!((a.slerp(&b, 1.0).dot(&b) - 1.0).abs() < 1e-12);
+    assert!((a.slerp(&a, 0.3).dot(&a) - 1.0).abs() < 1e-12);
+}
@@ -1,4 +1,5 @@
 use crate::mat2::*;
+use crate::vec2::*;
 use crate::vec3::*;
 
 pub type Colour = Vec3d;
@@ -8,6 +9,7 @@ pub type Direction3d = Vec3d;
 pub type Point2d = Vec2d;
 pub type Direction2d = Vec2d;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone)]
 pub struct Ray3d {
     pub origin: Point3d,
@@ -19,6 +21,7 @@ pub struct ConstrainedRay3d {
     pub range: (f64, f64),
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone)]
 pub struct Ray2d {
     pub origin: Point2d,
@@ -36,6 +39,7 @@ pub enum HitBoxResult {
     Outside(f64, f64),
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, Debug)]
 pub struct BoundingBox2d {
     pub u: Vec2d,
@@ -97,19 +101,14 @@ impl BoundingBox2d {
                 }
             }
         }
+        // A ray through an edge or corner hits several faces at (almost) the same point
+        result.sort_by(|a, b| a.total_cmp(b));
+        result.dedup_by(|a, b| (*a - *b).abs() <= 1e-12 * b.abs().max(1.0));
+
         match result.len() {
             0 => HitBoxResult::Miss,
-            1 => HitBoxResult::Inside(*result.get(0).unwrap()),
-            2 => {
-                let x = *result.get(0).unwrap();
-                let y = *result.get(1).unwrap();
-                if x < y {
-                    HitBoxResult::Outside(x, y)
-                } else {
-                    HitBoxResult::Outside(y, x)
-                }
-            }
-            _ => unreachable!(),
+            1 => HitBoxResult::Inside(*result.first().unwrap()),
+            _ => HitBoxResult::Outside(*result.first().unwrap(), *result.last().unwrap()),
         }
     }
 }
@@ -123,6 +122,7 @@ impl Default for BoundingBox2d {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, Debug)]
 pub struct BoundingBox3d {
     pub u: Vec3d,
@@ -198,19 +198,14 @@ impl BoundingBox3d {
                 }
             }
         }
+        // A ray through an edge or corner hits several faces at (almost) the same point
+        result.sort_by(|a, b| a.total_cmp(b));
+        result.dedup_by(|a, b| (*a - *b).abs() <= 1e-12 * b.abs().max(1.0));
+
         match result.len() {
             0 => HitBoxResult::Miss,
-            1 => HitBoxResult::Inside(*result.get(0).unwrap()),
-            2 => {
-                let x = *result.get(0).unwrap();
-                let y = *result.get(1).unwrap();
-                if x < y {
-                    HitBoxResult::Outside(x, y)
-                } else {
-                    HitBoxResult::Outside(y, x)
-                }
-            }
-            _ => unreachable!(),
+            1 => HitBoxResult::Inside(*result.first().unwrap()),
+            _ => HitBoxResult::Outside(*result.first().unwrap(), *result.last().unwrap()),
         }
     }
 }
@@ -230,7 +225,7 @@ impl Ray3d {
     }
 
     pub fn at(&self, t: f64) -> Point3d {
-        &(&self.direction * t) + &self.origin
+        (self.direction * t) + self.origin
     }
 }
 
@@ -240,7 +235,7 @@ impl Ray2d {
     }
 
     pub fn at(&self, t: f64) -> Point2d {
-        &(&self.direction * t) + &self.origin
+        (self.direction * t) + self.origin
     }
 
     pub fn get_ray_between_points(p1: &Point2d, p2: &Point2d) -> Ray2d {
@@ -265,12 +260,12 @@ impl Ray2d {
 
 #[cfg(test)]
 fn expect_eq(lhs: f64, rhs: f64) {
-    assert_eq!((lhs - rhs).abs() < 0.001, true);
+    assert!((lhs - rhs).abs() < 0.001);
 }
 
 #[cfg(test)]
 fn expect_eq_2d(lhs: &Point2d, rhs: &Point2d) {
-    assert_eq!((lhs - rhs).length() < 0.001, true);
+    assert!((lhs - rhs).length() < 0.001);
 }
 
 #[test]
@@ -279,8 +274,68 @@ fn test_ray2d_intersect() {
     let r2 = Ray2d::new(Point2d::new_raw(0.0, 2.0), Point2d::new_raw(0.1, 0.0));
     let alpha1 = r1.get_intersection(&r2).unwrap();
     let alpha2 = r2.get_intersection(&r1).unwrap();
-    expect_eq(4.0, f64::from(alpha1));
-    expect_eq(10.0, f64::from(alpha2));
+    expect_eq(4.0, alpha1);
+    expect_eq(10.0, alpha2);
 
     expect_eq_2d(&r1.at(alpha1), &r2.at(alpha2));
 }
+
+#[test]
+fn test_box_hit_by_ray() {
+    let square = BoundingBox2d::new(Point2d::new_raw(0.0, 0.0), Point2d::new_raw(1.0, 1.0));
+    let cray = |origin: (f64, f64), direction: (f64, f64)| ConstrainedRay2d {
+        ray: Ray2d::new(
+            Point2d::new_raw(origin.0, origin.1),
+            Point2d::new_raw(direction.0, direction.1),
+        ),
+        range: (0.0, 10.0),
+    };
+
+    let through = square.is_hit_by_ray(&cray((-1.0, 0.5), (1.0, 0.0)));
+    assert!(matches!(through, HitBoxResult::Outside(a, b) if a == 1.0 && b == 2.0));
+    let inside = square.is_hit_by_ray(&cray((0.5, 0.5), (1.0, 0.0)));
+    assert!(matches!(inside, HitBoxResult::Inside(a) if a == 0.5));
+    let miss = square.is_hit_by_ray(&cray((-1.0, 2.0), (1.0, 0.0)));
+    assert!(matches!(miss, HitBoxResult::Miss));
+
+    // Entering and leaving through corners hits three faces at once each time
+    let cube = BoundingBox3d {
+        u: Point3d::new_raw(0.0, 0.0, 0.0),
+        v: Point3d::new_raw(1.0, 1.0, 1.0),
+    };
+    let diagonal = cube.is_hit_by_ray(&ConstrainedRay3d {
+        ray: Ray3d::new(
+            Point3d::new_raw(-1.0, -1.0, -1.0),
+            Point3d::new_raw(1.0, 1.0, 1.0),
+        ),
+        range: (0.0, 10.0),
+    });
+    assert!(matches!(diagonal, HitBoxResult::Outside(a, b) if a == 1.0 && b == 2.0));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(value: &T) -> T {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    let ray = Ray3d::new(
+        Point3d::new_raw(0.1, -2.0, 1e-300),
+        Point3d::new_raw(1.0 / 3.0, 0.0, -7.5),
+    );
+    let copy = round_trip(&ray);
+    assert_eq!(copy.origin, ray.origin);
+    assert_eq!(copy.direction, ray.direction);
+
+    let ray = Ray2d::new(Point2d::new_raw(0.7, 1e20), Point2d::new_raw(-0.3, 0.1));
+    assert_eq!(round_trip(&ray).direction, ray.direction);
+
+    let enclosure = BoundingBox3d {
+        u: Point3d::new_raw(-1.0, 0.2, 0.3),
+        v: Point3d::new_raw(4.0, 5.5, 6.1),
+    };
+    assert_eq!(round_trip(&enclosure).v, enclosure.v);
+    let enclosure = BoundingBox2d::new(Point2d::new_raw(0.1, 0.2), Point2d::new_raw(0.3, 0.4));
+    assert_eq!(round_trip(&enclosure).u, enclosure.u);
+}
@@ -0,0 +1,508 @@
+use crate::kd_tree_traits::*;
+use crate::ray_box::*;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::ControlFlow;
+
+type Cell = [i64; 3]; // Unused dimensions stay 0
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpatialHashError {
+    NonFinitePosition,
+    NotAPoint, // The bounding box has an extent
+}
+
+impl std::fmt::Display for SpatialHashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SpatialHashError::NonFinitePosition => write!(f, "position is not finite"),
+            SpatialHashError::NotAPoint => write!(f, "bounding box is not a point"),
+        }
+    }
+}
+
+impl std::error::Error for SpatialHashError {}
+
+// The generation of a slot changes when its content is removed, so old handles stop resolving
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SpatialHashHandle {
+    pub(crate) index: u32,
+    pub(crate) generation: u32,
+}
+
+// Uniform grid of cells with the given size, only occupied cells are stored.
+// Contents have to be points, each is stored in the cell containing it.
+pub struct SpatialHash<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> {
+    contents: Vec<Option<(Content, Cell)>>,
+    generations: Vec<u32>,
+    free: Vec<u32>,
+    cells: HashMap<Cell, Vec<u32>>,
+    cell_size: f64,
+    size: usize,
+    _phantom: std::marker::PhantomData<BoundingBox>,
+}
+
+pub type SpatialHash2d<Content> = SpatialHash<BoundingBox2d, Content>;
+pub type SpatialHash3d<Content> = SpatialHash<BoundingBox3d, Content>;
+
+pub struct SpatialHashNeighbourhood<
+    'a,
+    BoundingBox: BoundingBoxTrait,
+    Content: KdTreeContent<BoundingBox>,
+> {
+    hash: &'a SpatialHash<BoundingBox, Content>,
+    cells: Vec<Cell>,
+    current: std::slice::Iter<'a, u32>,
+}
+
+impl<'a, BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>> Iterator
+    for SpatialHashNeighbourhood<'a, BoundingBox, Content>
+{
+    type Item = (SpatialHashHandle, &'a Content);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(&index) = self.current.next() {
+                let (content, _) = self.hash.contents[index as usize].as_ref().unwrap();
+                return Some((self.hash.handle(index), content));
+            }
+
+            let cell = self.cells.pop()?;
+            if let Some(indices) = self.hash.cells.get(&cell) {
+                self.current = indices.iter();
+            }
+        }
+    }
+}
+
+impl<BoundingBox: BoundingBoxTrait, Content: KdTreeContent<BoundingBox>>
+    SpatialHash<BoundingBox, Content>
+{
+    pub fn new(cell_size: f64) -> Self {
+        assert!(cell_size > 0.0 && cell_size.is_finite());
+        SpatialHash {
+            contents: vec![],
+            generations: vec![],
+            free: vec![],
+            cells: HashMap::new(),
+            cell_size,
+            size: 0,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    pub fn get_cell_size(&self) -> f64 {
+        self.cell_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn handle(&self, index: u32) -> SpatialHashHandle {
+        SpatialHashHandle {
+            index,
+            generation: self.generations[index as usize],
+        }
+    }
+
+    fn slot_mut(&mut self, handle: SpatialHashHandle) -> Option<&mut Option<(Content, Cell)>> {
+        if self.generations.get(handle.index as usize) != Some(&handle.generation) {
+            return None;
+        }
+        self.contents.get_mut(handle.index as usize)
+    }
+
+    pub fn get(&self, handle: SpatialHashHandle) -> Option<&Content> {
+        if self.generations.get(handle.index as usize) != Some(&handle.generation) {
+            return None;
+        }
+        self.contents
+            .get(handle.index as usize)?
+            .as_ref()
+            .map(|(content, _)| content)
+    }
+
+    // The cell of the center of the bounding box, which has to be finite
+    fn get_cell(&self, enclosure: &BoundingBox) -> Result<Cell, SpatialHashError> {
+        let mut cell = [0; 3];
+        for (i, key) in cell.iter_mut().enumerate().take(BoundingBox::DIMENSIONS) {
+            let (lower, upper) = enclosure.get_bounds(i);
+            let center = 0.5 * (lower + upper);
+            if !center.is_finite() {
+                return Err(SpatialHashError::NonFinitePosition);
+            }
+            *key = (center / self.cell_size).floor() as i64;
+        }
+        Ok(cell)
+    }
+
+    fn get_content_cell(&self, content: &Content) -> Result<Cell, SpatialHashError> {
+        let enclosure = content.get_bounding_box();
+        let cell = self.get_cell(&enclosure)?;
+        for i in 0..BoundingBox::DIMENSIONS {
+            let (lower, upper) = enclosure.get_bounds(i);
+            if lower != upper {
+                return Err(SpatialHashError::NotAPoint);
+            }
+        }
+        Ok(cell)
+    }
+
+    fn remove_from_cell(&mut self, cell: &Cell, index: u32) {
+        let indices = self.cells.get_mut(cell).unwrap();
+        let position = indices.iter().position(|i| *i == index).unwrap();
+        indices.swap_remove(position);
+        if indices.is_empty() {
+            self.cells.remove(cell);
+        }
+    }
+
+    pub fn add(&mut self, content: Content) -> SpatialHashHandle {
+        match self.try_add(content) {
+            Ok(handle) => handle,
+            Err(error) => panic!("Can't add content to SpatialHash: {}", error),
+        }
+    }
+
+    pub fn try_add(&mut self, content: Content) -> Result<SpatialHashHandle, SpatialHashError> {
+        let cell = self.get_content_cell(&content)?;
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.contents[index as usize] = Some((content, cell));
+                index
+            }
+            None => {
+                let index = u32::try_from(self.contents.len())
+                    .expect("SpatialHash content indices are exhausted");
+                self.contents.push(Some((content, cell)));
+                self.generations.push(0);
+                index
+            }
+        };
+        self.cells.entry(cell).or_default().push(index);
+        self.size += 1;
+        Ok(self.handle(index))
+    }
+
+    pub fn remove_handle(&mut self, handle: SpatialHashHandle) -> Option<Content> {
+        let (content, cell) = self.slot_mut(handle)?.take()?;
+        self.remove_from_cell(&cell, handle.index);
+        let generation = &mut self.generations[handle.index as usize];
+        *generation = generation.wrapping_add(1);
+        self.free.push(handle.index);
+        self.size -= 1;
+        Some(content)
+    }
+
+    // Replaces the content of a handle, returns whether it changed cells
+    pub fn move_handle(&mut self, handle: SpatialHashHandle, content: Content) -> bool {
+        let new_cell = match self.get_content_cell(&content) {
+            Ok(cell) => cell,
+            Err(error) => panic!("Can't move content in SpatialHash: {}", error),
+        };
+        let old_cell = match self.slot_mut(handle) {
+            Some(Some((old, cell))) => {
+                *old = content;
+                std::mem::replace(cell, new_cell)
+            }
+            _ => return false,
+        };
+
+        if old_cell == new_cell {
+            return false;
+        }
+        self.remove_from_cell(&old_cell, handle.index);
+        self.cells.entry(new_cell).or_default().push(handle.index);
+        true
+    }
+
+    // Everything in the cell of the position and the cells adjacent to it, which includes
+    // all contents within one cell size of the position. Nothing is near a non-finite position.
+    pub fn get_neighbourhood<C: KdTreeContent<BoundingBox>>(
+        &self,
+        position: &C,
+    ) -> SpatialHashNeighbourhood<'_, BoundingBox, Content> {
+        let mut cells: Vec<Cell> = self
+            .get_cell(&position.get_bounding_box())
+            .into_iter()
+            .collect();
+        for i in 0..BoundingBox::DIMENSIONS {
+            cells = cells
+                .iter()
+                .flat_map(|cell| {
+                    (-1..=1).map(move |offset| {
+                        let mut cell = *cell;
+                        cell[i] += offset;
+                        cell
+                    })
+                })
+                .collect();
+        }
+
+        SpatialHashNeighbourhood {
+            hash: self,
+            cells,
+            current: [].iter(),
+        }
+    }
+
+    fn query<B, F>(&self, filter: &BoundingBox, mut fun: F) -> ControlFlow<B>
+    where
+        F: FnMut(u32, &Content) -> ControlFlow<B>,
+    {
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        let mut cell_count = 1.0;
+        for i in 0..BoundingBox::DIMENSIONS {
+            let (filter_lower, filter_upper) = filter.get_bounds(i);
+            let first = (filter_lower / self.cell_size).floor();
+            let last = (filter_upper / self.cell_size).floor();
+            // Counted before the conversion, which saturates for huge or infinite bounds
+            cell_count *= last - first + 1.0;
+            lower[i] = first as i64;
+            upper[i] = last as i64;
+        }
+        // NaN bounds cover no cells
+        if cell_count.is_nan() || cell_count < 1.0 {
+            return ControlFlow::Continue(());
+        }
+        let is_covered = |cell: &Cell| (0..3).all(|i| lower[i] <= cell[i] && cell[i] <= upper[i]);
+
+        let mut visit = |indices: &Vec<u32>| {
+            for &index in indices {
+                let (content, _) = self.contents[index as usize].as_ref().unwrap();
+                if content.get_bounding_box().intersects(filter) {
+                    fun(index, content)?;
+                }
+            }
+            ControlFlow::Continue(())
+        };
+
+        // Large filters are cheaper to check against the occupied cells
+        if !cell_count.is_finite() || cell_count > self.cells.len() as f64 {
+            for (cell, indices) in &self.cells {
+                if is_covered(cell) {
+                    visit(indices)?;
+                }
+            }
+        } else {
+            let mut cell = lower;
+            loop {
+                if let Some(indices) = self.cells.get(&cell) {
+                    visit(indices)?;
+                }
+
+                let mut i = 0;
+                while i < 3 && cell[i] == upper[i] {
+                    cell[i] = lower[i];
+                    i += 1;
+                }
+                if i == 3 {
+                    break;
+                }
+                cell[i] += 1;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    pub fn query_with<B, F>(&self, filter: &BoundingBox, mut fun: F) -> ControlFlow<B>
+    where
+        F: FnMut(&Content) -> ControlFlow<B>,
+    {
+        self.query(filter, |_, content| fun(content))
+    }
+
+    pub fn get_intersection_handles(&self, filter: &BoundingBox) -> Vec<SpatialHashHandle> {
+        let mut result = vec![];
+        let _: ControlFlow<()> = self.query(filter, |index, _| {
+            result.push(self.handle(index));
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    pub fn get_intersection(&self, filter: &BoundingBox) -> HashSet<Content>
+    where
+        Content: Clone + Eq + Hash,
+    {
+        let mut result = HashSet::new();
+        let _: ControlFlow<()> = self.query(filter, |_, content| {
+            result.insert(content.clone());
+            ControlFlow::Continue(())
+        });
+        result
+    }
+}
+
+#[test]
+fn test_spatial_hash_2d() {
+    use crate::kd_tree::*;
+    use crate::traits::*;
+
+    let points: Vec<Point2d> = (0..2000)
+        .map(|i| {
+            let i = i as f64;
+            Point2d::new_raw((0.37 * i).sin() * 30.0, (0.73 * i).cos() * 20.0 - 3.0)
+        })
+        .collect();
+    let mut hash = SpatialHash2d::new(1.5);
+    let handles: Vec<SpatialHashHandle> = points.iter().map(|point| hash.add(*point)).collect();
+    assert_eq!(hash.len(), points.len());
+
+    let kd_tree = KdTree::build(points.clone());
+    let filters = [
+        BoundingBox2d::new(Point2d::new_raw(-6.0, -8.0), Point2d::new_raw(6.0, 2.0)),
+        BoundingBox2d::new(Point2d::new_raw(-40.0, -40.0), Point2d::new_raw(40.0, 10.0)),
+    ];
+    for filter in &filters {
+        assert_eq!(
+            hash.get_intersection(filter),
+            kd_tree.get_intersection(filter)
+        );
+    }
+    assert!(hash.get_intersection(&filters[0]).len() > 30);
+
+    // The number of covered cells doesn't fit into integers
+    let huge = BoundingBox2d::new(Point2d::new_raw(-1e30, -1e30), Point2d::new_raw(1e30, 1e30));
+    assert_eq!(hash.get_intersection(&huge).len(), points.len());
+    let half = BoundingBox2d::new(Point2d::new_raw(0.0, -1e30), Point2d::new_raw(1e30, 1e30));
+    assert_eq!(
+        hash.get_intersection(&half),
+        kd_tree.get_intersection(&half)
+    );
+
+    for (i, handle) in handles.iter().enumerate().step_by(2) {
+        let offset = Point2d::new_raw(0.01 * i as f64, 0.0);
+        hash.move_handle(*handle, points[i] + offset);
+    }
+    for handle in handles.iter().step_by(5) {
+        assert!(hash.remove_handle(*handle).is_some());
+    }
+    assert!(hash.remove_handle(handles[0]).is_none());
+    assert_eq!(hash.len(), 1600);
+
+    // Slots are reused, but old handles don't resolve to the new contents
+    let added = hash.add(points[0]);
+    let stale = *handles
+        .iter()
+        .find(|handle| handle.index == added.index)
+        .unwrap();
+    assert_ne!(stale, added);
+    assert_eq!(hash.get(stale), None);
+    assert!(!hash.move_handle(stale, points[1]));
+    assert!(hash.remove_handle(stale).is_none());
+    assert_eq!(hash.remove_handle(added), Some(points[0]));
+
+    let current: Vec<Point2d> = handles
+        .iter()
+        .filter_map(|h| hash.get(*h).cloned())
+        .collect();
+    let kd_tree = KdTree::build(current.clone());
+    for filter in &filters {
+        assert_eq!(
+            hash.get_intersection(filter),
+            kd_tree.get_intersection(filter)
+        );
+    }
+
+    // The neighbourhood contains everything within one cell size
+    for query in current.iter().take(50) {
+        let neighbours: HashSet<Point2d> = hash
+            .get_neighbourhood(query)
+            .map(|(handle, point)| {
+                assert_eq!(hash.get(handle), Some(point));
+                *point
+            })
+            .collect();
+        for point in &current {
+            if (point - query).length() <= 1.5 {
+                assert!(neighbours.contains(point));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_spatial_hash_3d() {
+    let mut hash = SpatialHash3d::new(1.0);
+    for i in 0..10 {
+        for j in 0..10 {
+            for k in 0..10 {
+                hash.add(Point3d::new_raw(
+                    i as f64 + 0.5,
+                    j as f64 + 0.5,
+                    k as f64 + 0.5,
+                ));
+            }
+        }
+    }
+
+    assert_eq!(
+        hash.get_neighbourhood(&Point3d::new_raw(5.2, 5.7, 5.1))
+            .count(),
+        27
+    );
+    assert_eq!(
+        hash.get_neighbourhood(&Point3d::new_raw(0.2, 0.7, 0.1))
+            .count(),
+        8
+    );
+    assert_eq!(
+        hash.get_neighbourhood(&Point3d::new_raw(-0.5, 5.5, 5.5))
+            .count(),
+        9
+    );
+
+    let filter = BoundingBox3d {
+        u: Point3d::new_raw(1.0, 2.0, 3.0),
+        v: Point3d::new_raw(3.0, 4.5, 3.6),
+    };
+    assert_eq!(hash.get_intersection_handles(&filter).len(), 2 * 3);
+    let mut count = 0;
+    let result = hash.query_with(&filter, |_| {
+        count += 1;
+        ControlFlow::Break(())
+    });
+    assert_eq!((result, count), (ControlFlow::Break(()), 1));
+}
+
+#[test]
+fn test_spatial_hash_try_add() {
+    let mut hash = SpatialHash2d::<BoundingBox2d>::new(1.0);
+    let point = Point2d::new_raw(2.5, -0.5);
+    let handle = hash.try_add(BoundingBox2d { u: point, v: point }).unwrap();
+
+    // Contents wider than a cell would be missed by queries next to their center
+    let wide = BoundingBox2d::new(Point2d::new_raw(-3.0, -3.0), Point2d::new_raw(3.0, 3.0));
+    assert_eq!(hash.try_add(wide).err(), Some(SpatialHashError::NotAPoint));
+
+    // Non-finite coordinates used to end up in cell 0 or at the edge of the grid
+    for position in [
+        Point2d::new_raw(f64::NAN, 0.5),
+        Point2d::new_raw(0.5, f64::INFINITY),
+    ] {
+        assert_eq!(
+            hash.try_add(BoundingBox2d {
+                u: position,
+                v: position
+            })
+            .err(),
+            Some(SpatialHashError::NonFinitePosition)
+        );
+        assert_eq!(hash.get_neighbourhood(&position).count(), 0);
+    }
+    assert_eq!(hash.len(), 1);
+
+    let nan = Point2d::new_raw(f64::NAN, f64::NAN);
+    assert!(hash
+        .get_intersection_handles(&BoundingBox2d { u: nan, v: nan })
+        .is_empty());
+    let around = BoundingBox2d::new(Point2d::new_raw(2.0, -1.0), Point2d::new_raw(3.0, 0.0));
+    assert_eq!(hash.get_intersection_handles(&around), vec![handle]);
+}
@@ -0,0 +1,110 @@
+#[cfg(test)]
+use crate::kd_tree::*;
+use crate::kd_tree_traits::*;
+use crate::ray_box::*;
+use crate::traits::*;
+#[cfg(test)]
+use std::collections::HashSet;
+use std::ops::Sub;
+
+// A disc in 2d, a sphere in 3d
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ball<Point> {
+    pub center: Point,
+    pub radius: f64,
+}
+
+pub type Disc2d = Ball<Point2d>;
+pub type Sphere3d = Ball<Point3d>;
+
+impl<Point: Sub<Output = Point> + Norm<Length = f64> + Copy> Ball<Point> {
+    pub fn new(center: Point, radius: f64) -> Self {
+        Self { center, radius }
+    }
+
+    pub fn contains_point(&self, point: &Point) -> bool {
+        (*point - self.center).length() <= self.radius
+    }
+
+    pub fn classify<BoundingBox: DistanceBoundingBoxTrait<Point = Point>>(
+        &self,
+        enclosure: &BoundingBox,
+    ) -> Overlap {
+        if enclosure.distance(&self.center) > self.radius {
+            Overlap::Outside
+        } else if enclosure.farthest_distance(&self.center) <= self.radius {
+            Overlap::Inside
+        } else {
+            Overlap::Partial
+        }
+    }
+}
+
+impl<Point, BoundingBox, Content> KdTreeRegion<BoundingBox, Content> for Ball<Point>
+where
+    Point: Sub<Output = Point> + Norm<Length = f64> + Copy,
+    BoundingBox: DistanceBoundingBoxTrait<Point = Point>,
+    Content: KdTreeContent<BoundingBox>,
+{
+    fn node_overlap(&self, enclosure: &BoundingBox) -> Overlap {
+        self.classify(enclosure)
+    }
+
+    fn contains(&self, content: &Content) -> bool {
+        content.get_bounding_box().distance(&self.center) <= self.radius
+    }
+}
+
+#[test]
+fn test_sphere() {
+    let sphere = Sphere3d::new(Point3d::new_raw(1.0, 2.0, 3.0), 2.0);
+    assert!(sphere.contains_point(&Point3d::new_raw(2.0, 3.0, 4.0)));
+    assert!(!sphere.contains_point(&Point3d::new_raw(2.2, 3.2, 4.2)));
+
+    let enclosure = |u: (f64, f64, f64), v: (f64, f64, f64)| BoundingBox3d {
+        u: Point3d::new_raw(u.0, u.1, u.2),
+        v: Point3d::new_raw(v.0, v.1, v.2),
+    };
+    assert_eq!(
+        sphere.classify(&enclosure((0.0, 1.0, 2.0), (2.0, 3.0, 4.0))),
+        Overlap::Inside
+    );
+    assert_eq!(
+        sphere.classify(&enclosure((0.0, 1.0, 2.0), (5.0, 3.0, 4.0))),
+        Overlap::Partial
+    );
+    // Near a corner of the box, but outside the sphere
+    assert_eq!(
+        sphere.classify(&enclosure((2.5, 3.5, 4.5), (5.0, 5.0, 5.0))),
+        Overlap::Outside
+    );
+
+    let points: Vec<Point3d> = (0..8000)
+        .map(|i| {
+            Point3d::new_raw(
+                0.5 * (i % 20) as f64 - 4.0,
+                0.5 * ((i / 20) % 20) as f64 - 3.0,
+                0.5 * (i / 400) as f64 - 2.0,
+            )
+        })
+        .collect();
+    let expected: HashSet<Point3d> = points
+        .iter()
+        .filter(|point| sphere.contains_point(point))
+        .cloned()
+        .collect();
+    assert!(expected.len() > 100 && expected.len() < points.len());
+
+    let kd_tree = KdTree::build(points.clone());
+    assert_eq!(kd_tree.get_in_region(&sphere), expected);
+    assert_eq!(kd_tree.freeze().get_in_region(&sphere), expected);
+
+    // Contents count as inside as soon as their box touches the sphere
+    let boxes = [
+        enclosure((2.0, 3.0, 4.0), (6.0, 6.0, 6.0)),
+        enclosure((2.5, 3.5, 4.5), (5.0, 5.0, 5.0)),
+    ];
+    let kd_tree = KdTree::build(boxes.to_vec());
+    assert_eq!(kd_tree.get_in_region_handles(&sphere).len(), 1);
+}
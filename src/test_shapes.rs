@@ -0,0 +1,93 @@
+use crate::kd_tree_traits::*;
+use crate::ray_box::*;
+
+// Contents shared by the tests of the spatial structures
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct TestSphere {
+    pub(crate) center: Point3d,
+}
+
+pub(crate) const TEST_SPHERE_RADIUS: f64 = 0.25;
+
+impl KdTreeContent<BoundingBox3d> for TestSphere {
+    fn get_bounding_box(&self) -> BoundingBox3d {
+        let radius = Point3d::new_raw(TEST_SPHERE_RADIUS, TEST_SPHERE_RADIUS, TEST_SPHERE_RADIUS);
+        BoundingBox3d {
+            u: self.center - radius,
+            v: self.center + radius,
+        }
+    }
+}
+
+pub(crate) fn hit_test_sphere(sphere: &TestSphere, cray: &ConstrainedRay3d) -> Option<f64> {
+    let oc = cray.ray.origin - sphere.center;
+    let a = cray.ray.direction.squared_length();
+    let half_b = oc.dot(&cray.ray.direction);
+    let c = oc.squared_length() - TEST_SPHERE_RADIUS * TEST_SPHERE_RADIUS;
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let t = (-half_b - discriminant.sqrt()) / a;
+    if cray.range.0 <= t && t <= cray.range.1 {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn get_test_spheres() -> Vec<TestSphere> {
+    // Two dense clusters far apart, the kind of input that midpoint splitting handles badly
+    let mut spheres = vec![];
+    for i in 0..12 {
+        for j in 0..12 {
+            for k in 0..3 {
+                let offset = if k == 1 { 40.0 } else { 0.0 };
+                spheres.push(TestSphere {
+                    center: Point3d::new_raw(
+                        0.3 * i as f64 + offset,
+                        0.3 * j as f64,
+                        0.6 * k as f64,
+                    ),
+                });
+            }
+        }
+    }
+    spheres
+}
+
+// The spheres of the 3d tests double as circles in the xy plane
+impl KdTreeContent<BoundingBox2d> for TestSphere {
+    fn get_bounding_box(&self) -> BoundingBox2d {
+        let radius = Point2d::new_raw(TEST_SPHERE_RADIUS, TEST_SPHERE_RADIUS);
+        let center = Point2d::new_raw(self.center.t[0], self.center.t[1]);
+        BoundingBox2d::new(center - radius, center + radius)
+    }
+}
+
+pub(crate) fn hit_test_circle(sphere: &TestSphere, cray: &ConstrainedRay2d) -> Option<f64> {
+    let [x, y] = cray.ray.origin.t;
+    let [dx, dy] = cray.ray.direction.t;
+    let cray = ConstrainedRay3d {
+        ray: Ray3d::new(
+            Point3d::new_raw(x, y, sphere.center.t[2]),
+            Direction3d::new_raw(dx, dy, 0.0),
+        ),
+        range: cray.range,
+    };
+    hit_test_sphere(sphere, &cray)
+}
+
+// Lets the tests use plain boxes as contents
+impl KdTreeContent<BoundingBox2d> for BoundingBox2d {
+    fn get_bounding_box(&self) -> BoundingBox2d {
+        self.clone()
+    }
+}
+
+impl KdTreeContent<BoundingBox3d> for BoundingBox3d {
+    fn get_bounding_box(&self) -> BoundingBox3d {
+        self.clone()
+    }
+}
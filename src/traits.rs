@@ -1,4 +1,7 @@
+// Relative to the largest matrix element, below this a determinant counts as zero
+pub(crate) const SINGULAR_TOLERANCE: f64 = 1e-12;
+
 pub trait Norm {
     type Length;
-    fn length(self: &Self) -> Self::Length;
+    fn length(&self) -> Self::Length;
 }
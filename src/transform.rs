@@ -0,0 +1,204 @@
+use crate::mat3::*;
+use crate::quat::*;
+use crate::ray_box::*;
+use crate::vec3::*;
+use std::ops::*;
+
+// Maps point p to linear * p + translation, directions only see the linear part
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug)]
+pub struct Transform3d {
+    pub linear: Mat3d,
+    pub translation: Vec3d,
+}
+
+impl Default for Transform3d {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform3d {
+    pub fn new(linear: Mat3d, translation: Vec3d) -> Self {
+        Self {
+            linear,
+            translation,
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(Mat3d::identity(), Vec3d::new_raw(0.0, 0.0, 0.0))
+    }
+
+    pub fn from_translation(translation: &Vec3d) -> Self {
+        Self::new(Mat3d::identity(), *translation)
+    }
+
+    pub fn from_rotation(rotation: &Quatd) -> Self {
+        Self::new(rotation.to_mat3(), Vec3d::new_raw(0.0, 0.0, 0.0))
+    }
+
+    pub fn from_scale(scale: &Vec3d) -> Self {
+        let [x, y, z] = scale.t;
+        Self::new(
+            Mat3d::new(
+                Vec3d::new_raw(x, 0.0, 0.0),
+                Vec3d::new_raw(0.0, y, 0.0),
+                Vec3d::new_raw(0.0, 0.0, z),
+            ),
+            Vec3d::new_raw(0.0, 0.0, 0.0),
+        )
+    }
+
+    // None if the linear part is singular
+    pub fn inverse(&self) -> Option<Self> {
+        let linear = self.linear.inverse()?;
+        let translation = -(&linear * &self.translation);
+        Some(Self::new(linear, translation))
+    }
+
+    pub fn transform_point(&self, point: &Point3d) -> Point3d {
+        &self.linear * point + self.translation
+    }
+
+    pub fn transform_direction(&self, direction: &Direction3d) -> Direction3d {
+        &self.linear * direction
+    }
+
+    // The direction is not normalized, so ray parameters stay the same in both spaces
+    pub fn transform_ray(&self, ray: &Ray3d) -> Ray3d {
+        Ray3d::new(
+            self.transform_point(&ray.origin),
+            self.transform_direction(&ray.direction),
+        )
+    }
+
+    pub fn transform_constrained_ray(&self, cray: &ConstrainedRay3d) -> ConstrainedRay3d {
+        ConstrainedRay3d {
+            ray: self.transform_ray(&cray.ray),
+            range: cray.range,
+        }
+    }
+
+    // Tight axis aligned bound of the transformed box
+    pub fn transform_box(&self, enclosure: &BoundingBox3d) -> BoundingBox3d {
+        let center = (enclosure.u + enclosure.v) * 0.5;
+        let extent = (enclosure.v - enclosure.u) * 0.5;
+
+        let center = self.transform_point(&center);
+        let mut new_extent = Vec3d::new_raw(0.0, 0.0, 0.0);
+        for i in 0..3 {
+            for j in 0..3 {
+                new_extent.t[i] += self.linear.r.t[i].t[j].abs() * extent.t[j];
+            }
+        }
+
+        BoundingBox3d {
+            u: center - new_extent,
+            v: center + new_extent,
+        }
+    }
+}
+
+// Applies rhs first
+impl Mul<&Transform3d> for &Transform3d {
+    type Output = Transform3d;
+
+    fn mul(self, rhs: &Transform3d) -> Self::Output {
+        Transform3d::new(
+            self.linear * rhs.linear,
+            self.transform_point(&rhs.translation),
+        )
+    }
+}
+
+impl Mul<Transform3d> for Transform3d {
+    type Output = Transform3d;
+
+    #[allow(clippy::op_ref)] // forwards to the by-reference impl
+    fn mul(self, rhs: Transform3d) -> Self::Output {
+        &self * &rhs
+    }
+}
+
+impl MulAssign<&Transform3d> for Transform3d {
+    fn mul_assign(&mut self, rhs: &Self) {
+        *self = &*self * rhs;
+    }
+}
+
+#[test]
+fn test_transform() {
+    let is_close = |a: &Vec3d, b: &Vec3d| (a - b).length() < 1e-12;
+    let rotation = Quatd::from_axis_angle(
+        &Direction3d::new_raw(0.0, 0.0, 1.0),
+        0.25 * std::f64::consts::PI,
+    );
+    let transform = Transform3d::from_translation(&Vec3d::new_raw(1.0, -2.0, 3.0))
+        * Transform3d::from_rotation(&rotation)
+        * Transform3d::from_scale(&Vec3d::new_raw(2.0, 2.0, 0.5));
+
+    let point = Point3d::new_raw(0.5, 1.0, -4.0);
+    let expected =
+        rotation.rotate(&(point * Vec3d::new_raw(2.0, 2.0, 0.5))) + Vec3d::new_raw(1.0, -2.0, 3.0);
+    assert!(is_close(&transform.transform_point(&point), &expected));
+    assert!(is_close(
+        &transform.transform_direction(&point),
+        &(expected - Vec3d::new_raw(1.0, -2.0, 3.0))
+    ));
+
+    let inverse = transform.inverse().unwrap();
+    assert!(is_close(&inverse.transform_point(&expected), &point));
+    let mut identity = inverse;
+    identity *= &transform;
+    assert!(is_close(&identity.transform_point(&point), &point));
+    assert!(Transform3d::from_scale(&Vec3d::new_raw(1.0, 0.0, 1.0))
+        .inverse()
+        .is_none());
+    assert!(is_close(
+        &Transform3d::default().transform_point(&point),
+        &point
+    ));
+
+    // Hits found in object space are at the same ray parameter in world space
+    let cray = ConstrainedRay3d {
+        ray: Ray3d::new(
+            Point3d::new_raw(-3.0, 0.2, 1.0),
+            Direction3d::new_raw(1.0, 0.1, 0.0),
+        ),
+        range: (0.0, 10.0),
+    };
+    let object_ray = inverse.transform_constrained_ray(&cray);
+    assert_eq!(object_ray.range, cray.range);
+    assert!(is_close(
+        &transform.transform_point(&object_ray.ray.at(2.5)),
+        &cray.ray.at(2.5)
+    ));
+
+    let enclosure = BoundingBox3d {
+        u: Point3d::new_raw(-1.0, -1.0, -2.0),
+        v: Point3d::new_raw(1.0, 1.0, 2.0),
+    };
+    let bound = transform.transform_box(&enclosure);
+    let half_diagonal = 2.0 * 2.0_f64.sqrt();
+    assert!(is_close(
+        &bound.u,
+        &Point3d::new_raw(1.0 - half_diagonal, -2.0 - half_diagonal, 2.0)
+    ));
+    assert!(is_close(
+        &bound.v,
+        &Point3d::new_raw(1.0 + half_diagonal, -2.0 + half_diagonal, 4.0)
+    ));
+    for i in 0..8 {
+        let mut corner = enclosure.u;
+        for j in 0..3 {
+            if i & (1 << j) != 0 {
+                corner.t[j] = enclosure.v.t[j];
+            }
+        }
+        let corner = transform.transform_point(&corner);
+        for j in 0..3 {
+            assert!(bound.u.t[j] - 1e-12 <= corner.t[j] && corner.t[j] <= bound.v.t[j] + 1e-12);
+        }
+    }
+}
@@ -3,6 +3,7 @@ use ordered_float::NotNan;
 use std::hash::{Hash, Hasher};
 use std::ops::*;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
 pub struct Vec2<T: Copy> {
     pub t: [T; 2],
@@ -28,7 +29,7 @@ impl<T: Add<T, Output = T> + Mul<T, Output = T> + Clone + Copy> Vec2<T> {
 
 impl Norm for Vec2<f64> {
     type Length = f64;
-    fn length(self: &Self) -> Self::Length {
+    fn length(&self) -> Self::Length {
         self.squared_length().sqrt()
     }
 }
@@ -285,6 +286,7 @@ impl Hash for Vec2d {
 }
 
 #[test]
+#[allow(clippy::op_ref)]
 fn test_vector_multiplication_scalar() {
     let vec1 = Vec2::<i32>::new(0, 1);
     let vec2 = Vec2::<i32>::new(-2, 3);
@@ -4,6 +4,7 @@ use ordered_float::NotNan;
 use std::hash::{Hash, Hasher};
 use std::ops::*;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Vec3<T: Copy> {
     pub t: [T; 3],
@@ -43,7 +44,7 @@ impl<T: Sub<T, Output = T> + Mul<T, Output = T> + Clone + Copy> Vec3<T> {
 
 impl Norm for Vec3<f64> {
     type Length = f64;
-    fn length(self: &Self) -> Self::Length {
+    fn length(&self) -> Self::Length {
         self.squared_length().sqrt()
     }
 }
@@ -0,0 +1,399 @@
+pub use crate::traits::*;
+use crate::vec3::*;
+use ordered_float::NotNan;
+use std::hash::{Hash, Hasher};
+use std::ops::*;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Vec4<T: Copy> {
+    pub t: [T; 4],
+}
+
+impl<T: Copy> Vec4<T> {
+    pub fn new(x: T, y: T, z: T, w: T) -> Self {
+        Self { t: [x, y, z, w] }
+    }
+
+    // Drops the last component
+    pub fn truncate(&self) -> Vec3<T> {
+        Vec3::new(self.t[0], self.t[1], self.t[2])
+    }
+}
+
+impl<T: Copy> Vec3<T> {
+    pub fn extend(&self, w: T) -> Vec4<T> {
+        Vec4::new(self.t[0], self.t[1], self.t[2], w)
+    }
+}
+
+impl<T: Add<T, Output = T> + Mul<T, Output = T> + Clone + Copy> Vec4<T> {
+    pub fn squared_length(self: &Vec4<T>) -> T {
+        self.t[0] * self.t[0]
+            + self.t[1] * self.t[1]
+            + self.t[2] * self.t[2]
+            + self.t[3] * self.t[3]
+    }
+}
+
+impl<T: Add<T, Output = T> + Mul<T, Output = T> + Clone + Copy> Vec4<T> {
+    pub fn dot(self: &Vec4<T>, other: &Vec4<T>) -> T {
+        self.t[0] * other.t[0]
+            + self.t[1] * other.t[1]
+            + self.t[2] * other.t[2]
+            + self.t[3] * other.t[3]
+    }
+}
+
+impl Norm for Vec4<f64> {
+    type Length = f64;
+    fn length(&self) -> Self::Length {
+        self.squared_length().sqrt()
+    }
+}
+
+impl<T: Neg<Output = T> + Copy> Neg for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn neg(self) -> Self::Output {
+        Vec4::<T> {
+            t: [-self.t[0], -self.t[1], -self.t[2], -self.t[3]],
+        }
+    }
+}
+
+impl<T: Neg<Output = T> + Copy> Neg for Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn neg(self) -> Self::Output {
+        Vec4::<T> {
+            t: [-self.t[0], -self.t[1], -self.t[2], -self.t[3]],
+        }
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<&Vec4<T>> for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn add(self, rhs: &Vec4<T>) -> Self::Output {
+        Vec4::<T> {
+            t: [
+                self.t[0] + rhs.t[0],
+                self.t[1] + rhs.t[1],
+                self.t[2] + rhs.t[2],
+                self.t[3] + rhs.t[3],
+            ],
+        }
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<Vec4<T>> for Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn add(mut self, rhs: Vec4<T>) -> Self::Output {
+        self.t[0] = self.t[0] + rhs.t[0];
+        self.t[1] = self.t[1] + rhs.t[1];
+        self.t[2] = self.t[2] + rhs.t[2];
+        self.t[3] = self.t[3] + rhs.t[3];
+        self
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<&Vec4<T>> for Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn add(mut self, rhs: &Vec4<T>) -> Self::Output {
+        self.t[0] = self.t[0] + rhs.t[0];
+        self.t[1] = self.t[1] + rhs.t[1];
+        self.t[2] = self.t[2] + rhs.t[2];
+        self.t[3] = self.t[3] + rhs.t[3];
+        self
+    }
+}
+
+impl<T: Add<T, Output = T> + Copy> Add<Vec4<T>> for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn add(self, mut rhs: Vec4<T>) -> Self::Output {
+        rhs.t[0] = self.t[0] + rhs.t[0];
+        rhs.t[1] = self.t[1] + rhs.t[1];
+        rhs.t[2] = self.t[2] + rhs.t[2];
+        rhs.t[3] = self.t[3] + rhs.t[3];
+        rhs
+    }
+}
+
+impl<'a, T: AddAssign<&'a T> + Copy> AddAssign<&'a Vec4<T>> for Vec4<T> {
+    fn add_assign(&mut self, rhs: &'a Self) {
+        self.t[0] += &rhs.t[0];
+        self.t[1] += &rhs.t[1];
+        self.t[2] += &rhs.t[2];
+        self.t[3] += &rhs.t[3];
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<&Vec4<T>> for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn sub(self, rhs: &Vec4<T>) -> Self::Output {
+        Vec4::<T> {
+            t: [
+                self.t[0] - rhs.t[0],
+                self.t[1] - rhs.t[1],
+                self.t[2] - rhs.t[2],
+                self.t[3] - rhs.t[3],
+            ],
+        }
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<Vec4<T>> for Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn sub(mut self, rhs: Vec4<T>) -> Self::Output {
+        self.t[0] = self.t[0] - rhs.t[0];
+        self.t[1] = self.t[1] - rhs.t[1];
+        self.t[2] = self.t[2] - rhs.t[2];
+        self.t[3] = self.t[3] - rhs.t[3];
+        self
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<&Vec4<T>> for Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn sub(mut self, rhs: &Vec4<T>) -> Self::Output {
+        self.t[0] = self.t[0] - rhs.t[0];
+        self.t[1] = self.t[1] - rhs.t[1];
+        self.t[2] = self.t[2] - rhs.t[2];
+        self.t[3] = self.t[3] - rhs.t[3];
+        self
+    }
+}
+
+impl<T: Sub<T, Output = T> + Copy> Sub<Vec4<T>> for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn sub(self, mut rhs: Vec4<T>) -> Self::Output {
+        rhs.t[0] = self.t[0] - rhs.t[0];
+        rhs.t[1] = self.t[1] - rhs.t[1];
+        rhs.t[2] = self.t[2] - rhs.t[2];
+        rhs.t[3] = self.t[3] - rhs.t[3];
+        rhs
+    }
+}
+
+impl<'a, T: SubAssign<&'a T> + Copy> SubAssign<&'a Vec4<T>> for Vec4<T> {
+    fn sub_assign(&mut self, rhs: &'a Self) {
+        self.t[0] -= &rhs.t[0];
+        self.t[1] -= &rhs.t[1];
+        self.t[2] -= &rhs.t[2];
+        self.t[3] -= &rhs.t[3];
+    }
+}
+
+impl<T: Mul<T, Output = T> + Copy> Mul<&Vec4<T>> for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn mul(self, rhs: &Vec4<T>) -> Self::Output {
+        Vec4::<T> {
+            t: [
+                self.t[0] * rhs.t[0],
+                self.t[1] * rhs.t[1],
+                self.t[2] * rhs.t[2],
+                self.t[3] * rhs.t[3],
+            ],
+        }
+    }
+}
+
+impl<T: Mul<T, Output = T> + Copy> Mul<Vec4<T>> for Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn mul(mut self, rhs: Vec4<T>) -> Self::Output {
+        self.t[0] = self.t[0] * rhs.t[0];
+        self.t[1] = self.t[1] * rhs.t[1];
+        self.t[2] = self.t[2] * rhs.t[2];
+        self.t[3] = self.t[3] * rhs.t[3];
+        self
+    }
+}
+
+impl<T: Mul<T, Output = T> + Copy> Mul<Vec4<T>> for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn mul(self, mut rhs: Vec4<T>) -> Self::Output {
+        rhs.t[0] = self.t[0] * rhs.t[0];
+        rhs.t[1] = self.t[1] * rhs.t[1];
+        rhs.t[2] = self.t[2] * rhs.t[2];
+        rhs.t[3] = self.t[3] * rhs.t[3];
+        rhs
+    }
+}
+
+impl<T: Mul<T, Output = T> + Copy> Mul<&Vec4<T>> for Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn mul(mut self, rhs: &Vec4<T>) -> Self::Output {
+        self.t[0] = self.t[0] * rhs.t[0];
+        self.t[1] = self.t[1] * rhs.t[1];
+        self.t[2] = self.t[2] * rhs.t[2];
+        self.t[3] = self.t[3] * rhs.t[3];
+        self
+    }
+}
+
+impl<'a, T: MulAssign<&'a T> + Copy> MulAssign<&'a Vec4<T>> for Vec4<T> {
+    fn mul_assign(&mut self, rhs: &'a Self) {
+        self.t[0] *= &rhs.t[0];
+        self.t[1] *= &rhs.t[1];
+        self.t[2] *= &rhs.t[2];
+        self.t[3] *= &rhs.t[3];
+    }
+}
+
+impl<T: Mul<T, Output = T> + Copy> Mul<T> for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn mul(self, rhs: T) -> Self::Output {
+        Vec4::<T> {
+            t: [
+                self.t[0] * rhs,
+                self.t[1] * rhs,
+                self.t[2] * rhs,
+                self.t[3] * rhs,
+            ],
+        }
+    }
+}
+
+impl<T: Mul<T, Output = T> + Copy> Mul<T> for Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn mul(mut self, rhs: T) -> Self::Output {
+        self.t[0] = self.t[0] * rhs;
+        self.t[1] = self.t[1] * rhs;
+        self.t[2] = self.t[2] * rhs;
+        self.t[3] = self.t[3] * rhs;
+        self
+    }
+}
+
+impl<T: MulAssign<T> + Copy> MulAssign<T> for Vec4<T> {
+    fn mul_assign(&mut self, rhs: T) {
+        self.t[0] *= rhs;
+        self.t[1] *= rhs;
+        self.t[2] *= rhs;
+        self.t[3] *= rhs;
+    }
+}
+
+impl<T: Div<T, Output = T> + Copy> Div<T> for &Vec4<T> {
+    type Output = Vec4<T>;
+
+    fn div(self, rhs: T) -> Self::Output {
+        Vec4::<T> {
+            t: [
+                self.t[0] / rhs,
+                self.t[1] / rhs,
+                self.t[2] / rhs,
+                self.t[3] / rhs,
+            ],
+        }
+    }
+}
+
+impl<T: DivAssign<T> + Copy> DivAssign<T> for Vec4<T> {
+    fn div_assign(&mut self, rhs: T) {
+        self.t[0] /= rhs;
+        self.t[1] /= rhs;
+        self.t[2] /= rhs;
+        self.t[3] /= rhs;
+    }
+}
+
+impl<T> Vec4<T>
+where
+    T: Copy,
+    Vec4<T>: Norm<Length = T> + DivAssign<T>,
+{
+    pub fn get_normalized(&self) -> Self {
+        let mut result = *self;
+        result /= self.length();
+        result
+    }
+}
+
+impl<T> Vec4<T>
+where
+    T: Copy,
+    Vec4<T>: Norm<Length = T> + DivAssign<T>,
+{
+    pub fn normalize(&mut self) {
+        let len = self.length();
+        self.div_assign(len);
+    }
+}
+
+impl Vec4d {
+    pub fn is_almost_zero(&self) -> bool {
+        self.squared_length() < 1e-14
+    }
+
+    // Divides by w, for points coming out of a projection
+    pub fn project(&self) -> Vec3d {
+        &self.truncate() / self.t[3]
+    }
+}
+
+pub type Vec4d = Vec4<f64>;
+
+impl Eq for Vec4d {}
+
+impl Hash for Vec4d {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        NotNan::<f64>::new(self.t[0]).unwrap().hash(state);
+        NotNan::<f64>::new(self.t[1]).unwrap().hash(state);
+        NotNan::<f64>::new(self.t[2]).unwrap().hash(state);
+        NotNan::<f64>::new(self.t[3]).unwrap().hash(state);
+    }
+}
+
+impl Vec4d {
+    pub fn new_raw(x: f64, y: f64, z: f64, w: f64) -> Self {
+        Vec4d::new(x, y, z, w)
+    }
+}
+
+#[test]
+#[allow(clippy::op_ref)]
+fn test_vec4_operators() {
+    let a = Vec4::<i32>::new(1, -2, 3, 4);
+    let b = Vec4::<i32>::new(5, 6, -7, 8);
+
+    assert_eq!(a + b, Vec4::new(6, 4, -4, 12));
+    assert_eq!(&a + &b, a + &b);
+    assert_eq!(&a - b, Vec4::new(-4, -8, 10, -4));
+    assert_eq!(a * b, Vec4::new(5, -12, -21, 32));
+    assert_eq!(&a * 2, Vec4::new(2, -4, 6, 8));
+    assert_eq!(-a, Vec4::new(-1, 2, -3, -4));
+    assert_eq!(a.dot(&b), 5 - 12 - 21 + 32);
+
+    let mut c = a;
+    c += &b;
+    c -= &a;
+    assert_eq!(c, b);
+    c *= 3;
+    c /= 3;
+    assert_eq!(c, b);
+
+    let nested: Vec4<Vec4<i32>> = Vec4::new(a, b, a, b);
+    assert_eq!((nested + nested).t[3], b * 2);
+
+    let point = Vec3d::new_raw(1.0, 2.0, 3.0);
+    let homogeneous = point.extend(1.0);
+    assert_eq!(homogeneous, Vec4d::new_raw(1.0, 2.0, 3.0, 1.0));
+    assert_eq!(homogeneous.truncate(), point);
+    assert_eq!((homogeneous * 2.0).project(), point);
+    assert_eq!(Vec4d::new_raw(0.0, 3.0, 0.0, 4.0).length(), 5.0);
+}